@@ -17,6 +17,9 @@
 extern crate state_map;
 extern crate test;
 
+use std::collections::HashMap;
+use std::mem;
+
 use state_map::StateMap;
 use test::Bencher;
 
@@ -125,3 +128,88 @@ fn bench_insert_member(b: &mut Bencher) {
 
     b.iter(|| state_map.insert("m.room.member", "", 4));
 }
+
+/// A state map with many custom types, each with a single empty state key.
+fn custom_types_state_map() -> StateMap<usize> {
+    (0..100)
+        .map(|i| ((format!("org.example.custom{}", i), String::new()), i))
+        .collect()
+}
+
+#[bench]
+fn bench_build_custom_types(b: &mut Bencher) {
+    b.iter(custom_types_state_map);
+}
+
+#[bench]
+fn bench_clone_custom_types(b: &mut Bencher) {
+    let state_map = custom_types_state_map();
+
+    b.iter(|| state_map.clone());
+}
+
+/// Estimates the heap size of the uncommon types of the state map if each
+/// type had its own `HashMap`, as they did before being stored in short lists.
+///
+/// This uses the same estimate as `StateMap::heap_size`, i.e. one control
+/// byte per slot on top of the key and value.
+fn nested_hash_map_heap_size(state_map: &StateMap<usize>) -> usize {
+    let mut others: HashMap<String, HashMap<String, usize>> = HashMap::new();
+    for ((t, s), &e) in state_map.iter() {
+        others
+            .entry(t.to_string())
+            .or_default()
+            .insert(s.to_string(), e);
+    }
+
+    let outer_entry_size = mem::size_of::<(String, HashMap<String, usize>)>() + 1;
+    let inner_entry_size = mem::size_of::<(String, usize)>() + 1;
+
+    others.capacity() * outer_entry_size
+        + others
+            .iter()
+            .map(|(t, h)| {
+                t.capacity()
+                    + h.capacity() * inner_entry_size
+                    + h.keys().map(String::capacity).sum::<usize>()
+            })
+            .sum::<usize>()
+}
+
+#[test]
+fn custom_types_heap_size() {
+    let state_map = custom_types_state_map();
+
+    let before = nested_hash_map_heap_size(&state_map);
+    let after = state_map.heap_size_breakdown().others;
+    assert!(
+        after < before,
+        "custom types use {} bytes, but {} as nested maps",
+        after,
+        before
+    );
+}
+
+#[bench]
+fn bench_get_custom_type(b: &mut Bencher) {
+    let state_map = custom_types_state_map();
+
+    b.iter(|| state_map.get("org.example.custom50", ""));
+}
+
+#[bench]
+fn bench_get_other_many_state_keys(b: &mut Bencher) {
+    let state_map: StateMap<_> = (0..100)
+        .map(|i| {
+            (
+                (
+                    "m.space.child".to_string(),
+                    format!("!room{}:example.com", i),
+                ),
+                i,
+            )
+        })
+        .collect();
+
+    b.iter(|| state_map.get("m.space.child", "!room50:example.com"));
+}
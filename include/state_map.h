@@ -0,0 +1,105 @@
+#ifndef STATE_MAP_H
+#define STATE_MAP_H
+
+/* Generated with cbindgen from src/capi.rs, do not edit by hand. */
+
+#include <stdbool.h>
+#include <stddef.h>
+
+/**
+ * An opaque state map from `(type, state_key)` to event ID.
+ */
+typedef struct CStateMap CStateMap;
+
+/**
+ * Callback invoked by `state_map_foreach` for each entry in the map.
+ *
+ * The type and state key are *not* NUL terminated, and so are passed along
+ * with their lengths. All pointers are only valid for the duration of the
+ * call.
+ */
+typedef void (*StateMapForeachCallback)(void *ctx,
+                                        const char *event_type,
+                                        size_t event_type_len,
+                                        const char *state_key,
+                                        size_t state_key_len,
+                                        const char *event_id);
+
+
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/**
+ * Creates a new empty state map. It must be freed with `state_map_free`.
+ */
+struct CStateMap *state_map_new(void);
+
+/**
+ * Frees a state map created with `state_map_new`. Passing NULL is a no-op.
+ *
+ * # Safety
+ *
+ * `map` must be NULL or a pointer returned by `state_map_new` that has not
+ * already been freed.
+ */
+void state_map_free(struct CStateMap *map);
+
+/**
+ * Inserts a copy of `event_id` into the map, replacing any existing entry.
+ *
+ * Returns false (and leaves the map untouched) if any argument is NULL or
+ * the type or state key are not valid UTF-8.
+ *
+ * # Safety
+ *
+ * `map` must be a valid state map, and the strings must be NULL or valid NUL
+ * terminated strings.
+ */
+bool state_map_insert(struct CStateMap *map,
+                      const char *event_type,
+                      const char *state_key,
+                      const char *event_id);
+
+/**
+ * Looks up the event ID for the given type and state key, returning NULL if
+ * there is no such entry.
+ *
+ * The returned string is owned by the map, and is only valid until the map
+ * is next modified or freed.
+ *
+ * # Safety
+ *
+ * `map` must be a valid state map, and the strings must be NULL or valid NUL
+ * terminated strings.
+ */
+const char *state_map_get(const struct CStateMap *map,
+                          const char *event_type,
+                          const char *state_key);
+
+/**
+ * Returns the number of entries in the map.
+ *
+ * # Safety
+ *
+ * `map` must be NULL or a valid state map.
+ */
+size_t state_map_len(const struct CStateMap *map);
+
+/**
+ * Calls `callback` with `ctx` for every entry in the map, in an unspecified
+ * order. The map must not be modified from within the callback.
+ *
+ * # Safety
+ *
+ * `map` must be a valid state map, and `callback` must be safe to call with
+ * `ctx`.
+ */
+void state_map_foreach(const struct CStateMap *map, StateMapForeachCallback callback, void *ctx);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* STATE_MAP_H */
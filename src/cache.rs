@@ -0,0 +1,377 @@
+// Copyright 2018 New Vector Ltd
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not
+// use this file except in compliance with the License. You may obtain a copy of
+// the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS, WITHOUT
+// WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied. See the
+// License for the specific language governing permissions and limitations under
+// the License.
+
+//! A size bounded LRU cache of `StateMap`s, e.g. keyed by state group.
+//!
+//! Entries may be partial, in which case they also record which keys are known
+//! to be absent. This mirrors the semantics of Synapse's `DictionaryCache`.
+
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt::Debug;
+use std::hash::{BuildHasher, Hash};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use {HeapSize, StateMap};
+
+/// An entry returned from a [`StateMapCache`].
+#[derive(Debug, Clone)]
+pub struct StateMapCacheEntry<E: Debug + Clone, S = RandomState> {
+    /// Whether the entry holds the full state map, rather than only some of
+    /// its keys.
+    pub full: bool,
+    /// Keys that are known to not be in the full state map.
+    pub known_absent: Arc<HashSet<(String, String), S>>,
+    /// The (possibly partial) state map.
+    pub value: Arc<StateMap<E, S>>,
+}
+
+impl<E, S> PartialEq for StateMapCacheEntry<E, S>
+where
+    E: Debug + Clone + PartialEq,
+    S: BuildHasher,
+{
+    fn eq(&self, other: &StateMapCacheEntry<E, S>) -> bool {
+        self.full == other.full
+            && self.known_absent == other.known_absent
+            && self.value == other.value
+    }
+}
+
+impl<E, S> Eq for StateMapCacheEntry<E, S>
+where
+    E: Debug + Clone + Eq,
+    S: BuildHasher,
+{
+}
+
+struct CacheNode<E: Debug + Clone, S> {
+    entry: StateMapCacheEntry<E, S>,
+    weight: usize,
+    last_used: u64,
+}
+
+struct CacheInner<K, E: Debug + Clone, S> {
+    entries: HashMap<K, CacheNode<E, S>>,
+    /// Keys ordered by when they were last used, oldest first.
+    lru: BTreeMap<u64, K>,
+    clock: u64,
+    total_weight: usize,
+}
+
+/// A thread safe LRU cache of `StateMap`s, bounded by the total size of the
+/// cached maps rather than by the number of maps.
+///
+/// A state map that on its own weighs more than the maximum is never cached.
+pub struct StateMapCache<K, E: Debug + Clone, S = RandomState> {
+    inner: Mutex<CacheInner<K, E, S>>,
+    max_weight: usize,
+    weigher: fn(&StateMap<E, S>) -> usize,
+}
+
+impl<K, E, S> StateMapCache<K, E, S>
+where
+    K: Clone + Hash + Eq,
+    E: Debug + Clone,
+    S: BuildHasher + Clone + Default,
+{
+    /// Creates a cache that holds at most `max_entries` state map entries in
+    /// total, across all cached maps.
+    pub fn with_max_entries(max_entries: usize) -> StateMapCache<K, E, S> {
+        StateMapCache::with_weigher(max_entries, StateMap::len)
+    }
+
+    /// Creates a cache whose total size, as measured by `weigher`, is at most
+    /// `max_weight`.
+    pub fn with_weigher(
+        max_weight: usize,
+        weigher: fn(&StateMap<E, S>) -> usize,
+    ) -> StateMapCache<K, E, S> {
+        StateMapCache {
+            inner: Mutex::new(CacheInner {
+                entries: HashMap::new(),
+                lru: BTreeMap::new(),
+                clock: 0,
+                total_weight: 0,
+            }),
+            max_weight,
+            weigher,
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, CacheInner<K, E, S>> {
+        // The cache is never left in an inconsistent state while locked, so
+        // it's fine to carry on if another thread panicked.
+        match self.inner.lock() {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    /// Fetches the entry for `key`, marking it as recently used.
+    ///
+    /// If `filter` is given, the returned state map (and set of known absent
+    /// keys) only includes those keys.
+    pub fn get(
+        &self,
+        key: &K,
+        filter: Option<&[(&str, &str)]>,
+    ) -> Option<StateMapCacheEntry<E, S>> {
+        let mut inner = self.lock();
+        let inner = &mut *inner;
+
+        let node = inner.entries.get_mut(key)?;
+
+        inner.clock += 1;
+        inner.lru.remove(&node.last_used);
+        inner.lru.insert(inner.clock, key.clone());
+        node.last_used = inner.clock;
+
+        let entry = &node.entry;
+        let filter = match filter {
+            Some(filter) => filter,
+            None => return Some(entry.clone()),
+        };
+
+        let mut value = StateMap::with_hasher(entry.value.hasher().clone());
+        let mut known_absent = HashSet::with_hasher(entry.value.hasher().clone());
+        for &(t, s) in filter {
+            if let Some(e) = entry.value.get(t, s) {
+                value.insert(t, s, e.clone());
+            } else if entry.known_absent.contains(&(t.to_string(), s.to_string())) {
+                known_absent.insert((t.to_string(), s.to_string()));
+            }
+        }
+
+        Some(StateMapCacheEntry {
+            full: entry.full,
+            known_absent: Arc::new(known_absent),
+            value: Arc::new(value),
+        })
+    }
+
+    /// Caches the full state map for `key`, replacing any existing entry.
+    pub fn insert_full(&self, key: K, value: StateMap<E, S>) {
+        let entry = StateMapCacheEntry {
+            full: true,
+            known_absent: Arc::new(HashSet::with_hasher(value.hasher().clone())),
+            value: Arc::new(value),
+        };
+
+        let mut inner = self.lock();
+        self.insert_entry(&mut inner, key, entry);
+    }
+
+    /// Adds some of the entries of the state map for `key` to the cache.
+    ///
+    /// `fetched_keys` are the keys that were requested, and any of those not
+    /// in `value` are recorded as known to be absent. The entries are merged
+    /// into any existing entry for `key`.
+    pub fn update_partial(&self, key: K, value: StateMap<E, S>, fetched_keys: &[(&str, &str)]) {
+        let mut inner = self.lock();
+
+        // Take the existing entry out of the cache so that we (usually) hold
+        // the only reference to its state map and known absent keys, and can
+        // update them in place.
+        let mut entry = match inner.entries.remove(&key) {
+            Some(node) => {
+                inner.lru.remove(&node.last_used);
+                inner.total_weight -= node.weight;
+                node.entry
+            }
+            None => StateMapCacheEntry {
+                full: false,
+                known_absent: Arc::new(HashSet::with_hasher(value.hasher().clone())),
+                value: Arc::new(StateMap::with_hasher(value.hasher().clone())),
+            },
+        };
+
+        {
+            let known_absent = Arc::make_mut(&mut entry.known_absent);
+            for &(t, s) in fetched_keys {
+                if !value.contains_key(t, s) {
+                    known_absent.insert((t.to_string(), s.to_string()));
+                }
+            }
+
+            let existing = Arc::make_mut(&mut entry.value);
+            for ((t, s), e) in value {
+                if !known_absent.is_empty() {
+                    known_absent.remove(&(t.clone(), s.clone()));
+                }
+                existing.insert(&t, &s, e);
+            }
+        }
+
+        self.insert_entry(&mut inner, key, entry);
+    }
+
+    fn insert_entry(
+        &self,
+        inner: &mut CacheInner<K, E, S>,
+        key: K,
+        entry: StateMapCacheEntry<E, S>,
+    ) {
+        let weight = (self.weigher)(&entry.value);
+
+        // Caching the entry would evict everything else and then the entry
+        // itself, so don't. Any existing entry for the key is now stale.
+        if weight > self.max_weight {
+            if let Some(old) = inner.entries.remove(&key) {
+                inner.lru.remove(&old.last_used);
+                inner.total_weight -= old.weight;
+            }
+            return;
+        }
+
+        inner.clock += 1;
+        let node = CacheNode {
+            entry,
+            weight,
+            last_used: inner.clock,
+        };
+
+        if let Some(old) = inner.entries.insert(key.clone(), node) {
+            inner.lru.remove(&old.last_used);
+            inner.total_weight -= old.weight;
+        }
+        inner.lru.insert(inner.clock, key);
+        inner.total_weight += weight;
+
+        while inner.total_weight > self.max_weight {
+            let oldest = match inner.lru.keys().next() {
+                Some(&oldest) => oldest,
+                None => break,
+            };
+            if let Some(key) = inner.lru.remove(&oldest) {
+                if let Some(node) = inner.entries.remove(&key) {
+                    inner.total_weight -= node.weight;
+                }
+            }
+        }
+    }
+
+    /// Removes the entry for `key` from the cache.
+    pub fn invalidate(&self, key: &K) {
+        let mut inner = self.lock();
+        if let Some(node) = inner.entries.remove(key) {
+            inner.lru.remove(&node.last_used);
+            inner.total_weight -= node.weight;
+        }
+    }
+
+    /// Removes all entries from the cache.
+    pub fn clear(&self) {
+        let mut inner = self.lock();
+        inner.entries.clear();
+        inner.lru.clear();
+        inner.total_weight = 0;
+    }
+
+    /// The number of cached state maps.
+    pub fn len(&self) -> usize {
+        self.lock().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The total size of all cached state maps, as measured by the cache's
+    /// weigher.
+    pub fn weight(&self) -> usize {
+        self.lock().total_weight
+    }
+}
+
+impl<K, E, S> StateMapCache<K, E, S>
+where
+    K: Clone + Hash + Eq,
+    E: Debug + Clone + HeapSize,
+    S: BuildHasher + Clone + Default,
+{
+    /// Creates a cache whose total estimated heap size, as given by
+    /// [`StateMap::heap_size`], is at most `max_bytes`.
+    pub fn with_max_heap_size(max_bytes: usize) -> StateMapCache<K, E, S> {
+        StateMapCache::with_weigher(max_bytes, StateMap::heap_size)
+    }
+}
+
+#[test]
+fn cache_eviction_test() {
+    use TYPE_MEMBERSHIP;
+
+    let cache = StateMapCache::with_max_entries(3);
+
+    let mut a = StateMap::new();
+    a.insert(TYPE_MEMBERSHIP, "@alice:example.com", 1);
+    a.insert(TYPE_MEMBERSHIP, "@bob:example.com", 2);
+
+    let mut b = StateMap::new();
+    b.insert(TYPE_MEMBERSHIP, "@carol:example.com", 3);
+
+    cache.insert_full(1, a.clone());
+    cache.insert_full(2, b.clone());
+    assert_eq!(cache.weight(), 3);
+
+    // Touch 1 so that 2 is the least recently used.
+    assert_eq!(*cache.get(&1, None).unwrap().value, a);
+
+    cache.insert_full(3, b);
+    assert_eq!(cache.len(), 2);
+    assert!(cache.get(&2, None).is_none());
+    assert!(cache.get(&1, None).is_some());
+    assert!(cache.get(&3, None).is_some());
+
+    // A map heavier than the whole cache isn't cached, and doesn't evict
+    // anything else.
+    let mut large = a.clone();
+    large.insert(TYPE_MEMBERSHIP, "@dave:example.com", 4);
+    large.insert(TYPE_MEMBERSHIP, "@erin:example.com", 5);
+    cache.insert_full(4, large);
+    assert_eq!(cache.len(), 2);
+    assert!(cache.get(&4, None).is_none());
+}
+
+#[test]
+fn cache_partial_test() {
+    use TYPE_MEMBERSHIP;
+
+    let cache = StateMapCache::with_max_entries(10);
+
+    let mut partial = StateMap::new();
+    partial.insert(TYPE_MEMBERSHIP, "@alice:example.com", 1);
+
+    cache.update_partial(
+        "group",
+        partial,
+        &[
+            (TYPE_MEMBERSHIP, "@alice:example.com"),
+            (TYPE_MEMBERSHIP, "@bob:example.com"),
+        ],
+    );
+
+    let entry = cache
+        .get(&"group", Some(&[(TYPE_MEMBERSHIP, "@bob:example.com")]))
+        .unwrap();
+    assert!(!entry.full);
+    assert!(entry.value.is_empty());
+    assert!(entry
+        .known_absent
+        .contains(&(TYPE_MEMBERSHIP.to_string(), "@bob:example.com".to_string())));
+
+    let entry = cache.get(&"group", None).unwrap();
+    assert_eq!(entry.value.len(), 1);
+    assert_eq!(entry.known_absent.len(), 1);
+}
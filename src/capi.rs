@@ -0,0 +1,212 @@
+// Copyright 2018 New Vector Ltd
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not
+// use this file except in compliance with the License. You may obtain a copy of
+// the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS, WITHOUT
+// WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied. See the
+// License for the specific language governing permissions and limitations under
+// the License.
+
+//! A C ABI for `StateMap`, enabled with the `capi` feature.
+//!
+//! The map stores its own copies of the event IDs as NUL terminated strings.
+//! The header for these functions lives in `include/state_map.h`, and is
+//! generated with `cbindgen --config cbindgen.toml --output include/state_map.h`.
+
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_void};
+use std::ptr;
+
+use StateMap;
+
+/// An opaque state map from `(type, state_key)` to event ID.
+pub struct CStateMap {
+    inner: StateMap<CString>,
+}
+
+/// Callback invoked by `state_map_foreach` for each entry in the map.
+///
+/// The type and state key are *not* NUL terminated, and so are passed along
+/// with their lengths. All pointers are only valid for the duration of the
+/// call.
+pub type StateMapForeachCallback = Option<
+    unsafe extern "C" fn(
+        ctx: *mut c_void,
+        event_type: *const c_char,
+        event_type_len: usize,
+        state_key: *const c_char,
+        state_key_len: usize,
+        event_id: *const c_char,
+    ),
+>;
+
+/// Converts a C string to a `&str`, returning `None` if it is NULL or not
+/// valid UTF-8.
+unsafe fn to_str<'a>(s: *const c_char) -> Option<&'a str> {
+    if s.is_null() {
+        return None;
+    }
+    CStr::from_ptr(s).to_str().ok()
+}
+
+/// Creates a new empty state map. It must be freed with `state_map_free`.
+#[no_mangle]
+pub extern "C" fn state_map_new() -> *mut CStateMap {
+    Box::into_raw(Box::new(CStateMap {
+        inner: StateMap::new(),
+    }))
+}
+
+/// Frees a state map created with `state_map_new`. Passing NULL is a no-op.
+///
+/// # Safety
+///
+/// `map` must be NULL or a pointer returned by `state_map_new` that has not
+/// already been freed.
+#[no_mangle]
+pub unsafe extern "C" fn state_map_free(map: *mut CStateMap) {
+    if !map.is_null() {
+        drop(Box::from_raw(map));
+    }
+}
+
+/// Inserts a copy of `event_id` into the map, replacing any existing entry.
+///
+/// Returns false (and leaves the map untouched) if any argument is NULL or
+/// the type or state key are not valid UTF-8.
+///
+/// # Safety
+///
+/// `map` must be a valid state map, and the strings must be NULL or valid NUL
+/// terminated strings.
+#[no_mangle]
+pub unsafe extern "C" fn state_map_insert(
+    map: *mut CStateMap,
+    event_type: *const c_char,
+    state_key: *const c_char,
+    event_id: *const c_char,
+) -> bool {
+    let map = match map.as_mut() {
+        Some(map) => map,
+        None => return false,
+    };
+
+    match (to_str(event_type), to_str(state_key), event_id.is_null()) {
+        (Some(t), Some(s), false) => {
+            let event_id = CStr::from_ptr(event_id).to_owned();
+            map.inner.insert(t, s, event_id);
+            true
+        }
+        _ => false,
+    }
+}
+
+/// Looks up the event ID for the given type and state key, returning NULL if
+/// there is no such entry.
+///
+/// The returned string is owned by the map, and is only valid until the map
+/// is next modified or freed.
+///
+/// # Safety
+///
+/// `map` must be a valid state map, and the strings must be NULL or valid NUL
+/// terminated strings.
+#[no_mangle]
+pub unsafe extern "C" fn state_map_get(
+    map: *const CStateMap,
+    event_type: *const c_char,
+    state_key: *const c_char,
+) -> *const c_char {
+    let map = match map.as_ref() {
+        Some(map) => map,
+        None => return ptr::null(),
+    };
+
+    match (to_str(event_type), to_str(state_key)) {
+        (Some(t), Some(s)) => map.inner.get(t, s).map_or(ptr::null(), |e| e.as_ptr()),
+        _ => ptr::null(),
+    }
+}
+
+/// Returns the number of entries in the map.
+///
+/// # Safety
+///
+/// `map` must be NULL or a valid state map.
+#[no_mangle]
+pub unsafe extern "C" fn state_map_len(map: *const CStateMap) -> usize {
+    map.as_ref().map_or(0, |map| map.inner.len())
+}
+
+/// Calls `callback` with `ctx` for every entry in the map, in an unspecified
+/// order. The map must not be modified from within the callback.
+///
+/// # Safety
+///
+/// `map` must be a valid state map, and `callback` must be safe to call with
+/// `ctx`.
+#[no_mangle]
+pub unsafe extern "C" fn state_map_foreach(
+    map: *const CStateMap,
+    callback: StateMapForeachCallback,
+    ctx: *mut c_void,
+) {
+    let (map, callback) = match (map.as_ref(), callback) {
+        (Some(map), Some(callback)) => (map, callback),
+        _ => return,
+    };
+
+    for ((t, s), e) in map.inner.iter() {
+        callback(
+            ctx,
+            t.as_ptr() as *const c_char,
+            t.len(),
+            s.as_ptr() as *const c_char,
+            s.len(),
+            e.as_ptr(),
+        );
+    }
+}
+
+#[test]
+fn capi_test() {
+    let map = state_map_new();
+
+    unsafe {
+        let t = CString::new("m.room.member").unwrap();
+        let s = CString::new("@alice:example.com").unwrap();
+        let e = CString::new("$alice").unwrap();
+
+        assert!(state_map_insert(map, t.as_ptr(), s.as_ptr(), e.as_ptr()));
+        assert!(!state_map_insert(map, t.as_ptr(), ptr::null(), e.as_ptr()));
+        assert_eq!(state_map_len(map), 1);
+
+        let found = state_map_get(map, t.as_ptr(), s.as_ptr());
+        assert_eq!(CStr::from_ptr(found), e.as_c_str());
+
+        let missing = CString::new("@bob:example.com").unwrap();
+        assert!(state_map_get(map, t.as_ptr(), missing.as_ptr()).is_null());
+
+        unsafe extern "C" fn count(
+            ctx: *mut c_void,
+            _: *const c_char,
+            _: usize,
+            _: *const c_char,
+            _: usize,
+            _: *const c_char,
+        ) {
+            *(ctx as *mut usize) += 1;
+        }
+
+        let mut seen = 0usize;
+        state_map_foreach(map, Some(count), &mut seen as *mut usize as *mut c_void);
+        assert_eq!(seen, 1);
+
+        state_map_free(map);
+    }
+}
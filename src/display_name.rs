@@ -0,0 +1,251 @@
+// Copyright 2018 New Vector Ltd
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not
+// use this file except in compliance with the License. You may obtain a copy of
+// the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS, WITHOUT
+// WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied. See the
+// License for the specific language governing permissions and limitations under
+// the License.
+
+//! Calculation of room and member display names, following the "Calculating
+//! the display name for a room" section of the client-server spec.
+
+use std::fmt::Debug;
+use std::hash::BuildHasher;
+
+use {room_summary, Membership, MembershipEvent, StateMap, WellKnownEmptyKeys};
+
+/// Gives access to the fields of state event content used to name rooms.
+///
+/// Each method only needs to return a value for events of the corresponding
+/// type.
+pub trait RoomNameEvent: MembershipEvent {
+    /// The `name` of an `m.room.name` event.
+    fn room_name(&self) -> Option<&str> {
+        None
+    }
+
+    /// The `alias` of an `m.room.canonical_alias` event.
+    fn canonical_alias(&self) -> Option<&str> {
+        None
+    }
+
+    /// The `displayname` of an `m.room.member` event.
+    fn displayname(&self) -> Option<&str> {
+        None
+    }
+}
+
+/// Returns the display name of the given member. The user ID is appended if
+/// another joined or invited member has the same display name, and the user
+/// ID alone is used if they don't have one.
+pub fn member_display_name<E, S>(state_map: &StateMap<E, S>, user: &str) -> String
+where
+    E: Debug + Clone + RoomNameEvent,
+    S: BuildHasher + Clone,
+{
+    let displayname = match state_map
+        .get_membership(user)
+        .and_then(|e| e.displayname())
+        .filter(|d| !d.is_empty())
+    {
+        Some(displayname) => displayname,
+        None => return user.to_string(),
+    };
+
+    let ambiguous = state_map.iter_members().any(|(u, e)| {
+        u != user && is_current(e.membership()) && e.displayname() == Some(displayname)
+    });
+
+    if ambiguous {
+        format!("{} ({})", displayname, user)
+    } else {
+        displayname.to_string()
+    }
+}
+
+fn is_current(membership: Membership) -> bool {
+    membership == Membership::Join || membership == Membership::Invite
+}
+
+/// Joins the names into a list, e.g. "Alice, Bob and Charlie".
+fn join_names(names: &[String]) -> String {
+    match names.split_last() {
+        None => String::new(),
+        Some((last, [])) => last.clone(),
+        Some((last, rest)) => format!("{} and {}", rest.join(", "), last),
+    }
+}
+
+/// Calculates the name of the room with the given state, as seen by `me`.
+///
+/// This is the room's `m.room.name` if it has one, then its canonical alias,
+/// and otherwise a name composed from the display names of the room's heroes
+/// (see [`room_summary`]).
+pub fn room_display_name<E, S>(state_map: &StateMap<E, S>, me: &str) -> String
+where
+    E: Debug + Clone + RoomNameEvent,
+    S: BuildHasher + Clone,
+{
+    let name = state_map
+        .get_well_known(WellKnownEmptyKeys::Name)
+        .and_then(|e| e.room_name());
+    if let Some(name) = name.filter(|n| !n.is_empty()) {
+        return name.to_string();
+    }
+
+    let alias = state_map
+        .get_well_known(WellKnownEmptyKeys::CanonicalAliases)
+        .and_then(|e| e.canonical_alias());
+    if let Some(alias) = alias.filter(|a| !a.is_empty()) {
+        return alias.to_string();
+    }
+
+    let summary = room_summary(state_map, me);
+    let members = summary.joined_member_count + summary.invited_member_count;
+
+    let mut names: Vec<_> = summary
+        .heroes
+        .iter()
+        .map(|hero| member_display_name(state_map, hero))
+        .collect();
+
+    if members <= 1 {
+        if names.is_empty() {
+            "Empty Room".to_string()
+        } else {
+            format!("Empty Room (was {})", join_names(&names))
+        }
+    } else if names.is_empty() {
+        "Empty Room".to_string()
+    } else if names.len() < members - 1 {
+        let others = members - 1 - names.len();
+        names.push(format!(
+            "{} {}",
+            others,
+            if others == 1 { "other" } else { "others" }
+        ));
+        join_names(&names)
+    } else {
+        join_names(&names)
+    }
+}
+
+#[test]
+fn room_display_name_test() {
+    use {TYPE_CANONICAL_ALIASES, TYPE_MEMBERSHIP, TYPE_NAME};
+
+    #[derive(Debug, Clone)]
+    enum Event {
+        Name(&'static str),
+        Alias(&'static str),
+        Member(Membership, Option<&'static str>),
+    }
+
+    impl MembershipEvent for Event {
+        fn membership(&self) -> Membership {
+            match *self {
+                Event::Member(m, _) => m,
+                _ => Membership::Leave,
+            }
+        }
+
+        fn stream_ordering(&self) -> i64 {
+            0
+        }
+    }
+
+    impl RoomNameEvent for Event {
+        fn room_name(&self) -> Option<&str> {
+            match *self {
+                Event::Name(n) => Some(n),
+                _ => None,
+            }
+        }
+
+        fn canonical_alias(&self) -> Option<&str> {
+            match *self {
+                Event::Alias(a) => Some(a),
+                _ => None,
+            }
+        }
+
+        fn displayname(&self) -> Option<&str> {
+            match *self {
+                Event::Member(_, d) => d,
+                _ => None,
+            }
+        }
+    }
+
+    let me = "@me:example.org";
+    fn member(state_map: &mut StateMap<Event>, user: &str, m: Membership, d: Option<&'static str>) {
+        state_map.insert(TYPE_MEMBERSHIP, user, Event::Member(m, d));
+    }
+
+    let mut state_map = StateMap::new();
+    member(&mut state_map, me, Membership::Join, Some("Me"));
+    assert_eq!(room_display_name(&state_map, me), "Empty Room");
+
+    member(
+        &mut state_map,
+        "@alice:example.org",
+        Membership::Leave,
+        Some("Alice"),
+    );
+    assert_eq!(room_display_name(&state_map, me), "Empty Room (was Alice)");
+
+    member(
+        &mut state_map,
+        "@alice:example.org",
+        Membership::Join,
+        Some("Alice"),
+    );
+    assert_eq!(room_display_name(&state_map, me), "Alice");
+
+    member(&mut state_map, "@bob:example.org", Membership::Invite, None);
+    assert_eq!(
+        room_display_name(&state_map, me),
+        "Alice and @bob:example.org"
+    );
+
+    // Display names that clash are disambiguated with the user ID.
+    member(
+        &mut state_map,
+        "@charlie:example.org",
+        Membership::Join,
+        Some("Alice"),
+    );
+    assert_eq!(
+        room_display_name(&state_map, me),
+        "Alice (@alice:example.org), @bob:example.org and Alice (@charlie:example.org)"
+    );
+
+    for i in 0..5 {
+        let user = format!("@user{}:example.org", i);
+        member(&mut state_map, &user, Membership::Join, None);
+    }
+    assert_eq!(
+        room_display_name(&state_map, me),
+        "Alice (@alice:example.org), @bob:example.org, Alice (@charlie:example.org), \
+         @user0:example.org, @user1:example.org and 3 others"
+    );
+
+    state_map.insert(
+        TYPE_CANONICAL_ALIASES,
+        "",
+        Event::Alias("#room:example.org"),
+    );
+    assert_eq!(room_display_name(&state_map, me), "#room:example.org");
+
+    state_map.insert(TYPE_NAME, "", Event::Name(""));
+    assert_eq!(room_display_name(&state_map, me), "#room:example.org");
+
+    state_map.insert(TYPE_NAME, "", Event::Name("The Room"));
+    assert_eq!(room_display_name(&state_map, me), "The Room");
+}
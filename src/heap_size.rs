@@ -0,0 +1,262 @@
+// Copyright 2018 New Vector Ltd
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not
+// use this file except in compliance with the License. You may obtain a copy of
+// the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS, WITHOUT
+// WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied. See the
+// License for the specific language governing permissions and limitations under
+// the License.
+
+//! Estimation of the heap memory used by a `StateMap`.
+
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::hash::BuildHasher;
+use std::mem;
+use std::rc::Rc;
+use std::sync::Arc;
+
+use small_map::SmallMap;
+use {StateKey, StateMap};
+
+/// Types that can report how many bytes they own on the heap.
+///
+/// This should not include `mem::size_of::<Self>()`, as that is accounted for
+/// by the containing collection.
+pub trait HeapSize {
+    /// Returns the number of heap allocated bytes owned by the value.
+    fn heap_size(&self) -> usize;
+}
+
+macro_rules! impl_heap_size_zero {
+    ($($t:ty),*) => {
+        $(
+            impl HeapSize for $t {
+                fn heap_size(&self) -> usize {
+                    0
+                }
+            }
+        )*
+    };
+}
+
+impl_heap_size_zero!(
+    (),
+    bool,
+    char,
+    u8,
+    u16,
+    u32,
+    u64,
+    u128,
+    usize,
+    i8,
+    i16,
+    i32,
+    i64,
+    i128,
+    isize,
+    f32,
+    f64
+);
+
+impl HeapSize for &str {
+    fn heap_size(&self) -> usize {
+        0
+    }
+}
+
+impl HeapSize for String {
+    fn heap_size(&self) -> usize {
+        self.capacity()
+    }
+}
+
+impl HeapSize for Box<str> {
+    fn heap_size(&self) -> usize {
+        self.len()
+    }
+}
+
+impl<T: HeapSize> HeapSize for Box<T> {
+    fn heap_size(&self) -> usize {
+        mem::size_of::<T>() + (**self).heap_size()
+    }
+}
+
+impl<T: HeapSize> HeapSize for Option<T> {
+    fn heap_size(&self) -> usize {
+        self.as_ref().map_or(0, HeapSize::heap_size)
+    }
+}
+
+impl<T: HeapSize> HeapSize for Vec<T> {
+    fn heap_size(&self) -> usize {
+        self.capacity() * mem::size_of::<T>() + self.iter().map(HeapSize::heap_size).sum::<usize>()
+    }
+}
+
+/// Shared pointers are counted as owning nothing, since the allocation is
+/// shared with (and generally accounted for by) other owners.
+impl<T: ?Sized> HeapSize for Arc<T> {
+    fn heap_size(&self) -> usize {
+        0
+    }
+}
+
+/// Shared pointers are counted as owning nothing, since the allocation is
+/// shared with (and generally accounted for by) other owners.
+impl<T: ?Sized> HeapSize for Rc<T> {
+    fn heap_size(&self) -> usize {
+        0
+    }
+}
+
+/// The type of all but `Other` keys is implied by the variant, so only their
+/// state keys are counted.
+impl HeapSize for StateKey {
+    fn heap_size(&self) -> usize {
+        match *self {
+            StateKey::WellKnown(_) => 0,
+            StateKey::Member(ref s)
+            | StateKey::Aliases(ref s)
+            | StateKey::ThirdPartyInvite(ref s) => s.capacity(),
+            StateKey::Other(ref t, ref s) => t.capacity() + s.capacity(),
+        }
+    }
+}
+
+/// A per bucket breakdown of the heap memory used by a `StateMap`, as returned
+/// by [`StateMap::heap_size_breakdown`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct HeapSizeBreakdown {
+    /// Bytes used by entries with well known types and empty state keys.
+    pub well_known: usize,
+    /// Bytes used by `m.room.member` entries.
+    pub membership: usize,
+    /// Bytes used by `m.room.aliases` entries.
+    pub aliases: usize,
+    /// Bytes used by `m.room.third_party_invite` entries.
+    pub invites: usize,
+    /// Bytes used by all other entries, including the inner per type maps.
+    pub others: usize,
+    /// Bytes used by the list of types marked as partial.
+    pub partial_types: usize,
+}
+
+impl HeapSizeBreakdown {
+    /// The total number of bytes across all buckets.
+    pub fn total(&self) -> usize {
+        self.well_known
+            + self.membership
+            + self.aliases
+            + self.invites
+            + self.others
+            + self.partial_types
+    }
+}
+
+/// Estimates the size of the table allocated by a `HashMap`, ignoring the
+/// contents of the keys and values.
+///
+/// This assumes one control byte per slot on top of the key and value, which
+/// is close to what the standard library's implementation uses.
+pub(crate) fn table_size<K, V, S>(map: &HashMap<K, V, S>) -> usize {
+    map.capacity() * (mem::size_of::<K>() + mem::size_of::<V>() + 1)
+}
+
+fn string_map_size<E, S>(map: &HashMap<String, E, S>) -> usize
+where
+    E: HeapSize,
+{
+    table_size(map)
+        + map
+            .iter()
+            .map(|(k, e)| k.capacity() + e.heap_size())
+            .sum::<usize>()
+}
+
+fn type_map_size<E, S>(map: &SmallMap<E, S>) -> usize
+where
+    E: HeapSize,
+{
+    map.allocated_size()
+        + map
+            .iter()
+            .map(|(k, e)| k.capacity() + e.heap_size())
+            .sum::<usize>()
+}
+
+impl<E, S> StateMap<E, S>
+where
+    E: Debug + Clone + HeapSize,
+    S: BuildHasher + Clone,
+{
+    /// Estimates the number of bytes allocated on the heap by the state map,
+    /// including the heap allocations owned by each value.
+    pub fn heap_size(&self) -> usize {
+        self.heap_size_breakdown().total()
+    }
+
+    /// Like [`StateMap::heap_size`], but reports the bytes used by each of
+    /// the internal buckets separately.
+    pub fn heap_size_breakdown(&self) -> HeapSizeBreakdown {
+        // The well known entries are stored inline, so only the heap usage of
+        // the values themselves counts.
+        let well_known = self
+            .well_known
+            .values()
+            .map(HeapSize::heap_size)
+            .sum::<usize>();
+
+        let others = table_size(&self.others)
+            + self
+                .others
+                .iter()
+                .map(|(t, h)| t.capacity() + type_map_size(h))
+                .sum::<usize>();
+
+        HeapSizeBreakdown {
+            well_known,
+            membership: string_map_size(&self.membership),
+            aliases: string_map_size(&self.aliases),
+            invites: string_map_size(&self.invites),
+            others,
+            partial_types: self.partial_types.heap_size(),
+        }
+    }
+}
+
+#[test]
+fn heap_size_test() {
+    use {TYPE_MEMBERSHIP, TYPE_POWER_LEVELS};
+
+    let mut state_map = StateMap::new();
+    assert_eq!(state_map.heap_size(), 0);
+
+    state_map.insert(TYPE_POWER_LEVELS, "", String::from("$power_levels"));
+    state_map.insert(
+        TYPE_MEMBERSHIP,
+        "@alice:example.com",
+        String::from("$alice"),
+    );
+
+    let breakdown = state_map.heap_size_breakdown();
+    assert!(breakdown.well_known >= "$power_levels".len());
+    assert!(breakdown.membership >= "@alice:example.com".len() + "$alice".len());
+    assert_eq!(breakdown.aliases, 0);
+    assert_eq!(breakdown.invites, 0);
+    assert_eq!(breakdown.others, 0);
+    assert_eq!(state_map.heap_size(), breakdown.total());
+
+    state_map.insert("m.custom", "", String::from("$custom"));
+    assert!(state_map.heap_size_breakdown().others >= "m.custom".len() + "$custom".len());
+
+    state_map.mark_partial("m.custom");
+    assert!(state_map.heap_size_breakdown().partial_types >= "m.custom".len());
+}
@@ -0,0 +1,135 @@
+// Copyright 2018 New Vector Ltd
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not
+// use this file except in compliance with the License. You may obtain a copy of
+// the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS, WITHOUT
+// WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied. See the
+// License for the specific language governing permissions and limitations under
+// the License.
+
+//! A reference counted string interner, so that strings repeated across the
+//! indexes of a `RoomStateIndex` (e.g. user and room IDs) are only stored once.
+
+use std::collections::HashMap;
+use std::hash::BuildHasher;
+use std::sync::Arc;
+
+/// An interned string, only meaningful for the [`Interner`] that created it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub(crate) struct Symbol(u32);
+
+/// Maps strings to `Symbol`s and back.
+///
+/// Each symbol is reference counted: every call to [`Interner::intern`] must
+/// be matched by a call to [`Interner::release`], and the string is freed
+/// once the count drops to zero. Freed symbols are reused for new strings.
+#[derive(Debug, Clone)]
+pub(crate) struct Interner<S> {
+    ids: HashMap<Arc<str>, Symbol, S>,
+    /// The string and reference count of each symbol, or `None` if the
+    /// symbol has been freed.
+    slots: Vec<Option<(Arc<str>, usize)>>,
+    free: Vec<Symbol>,
+}
+
+impl<S> Interner<S>
+where
+    S: BuildHasher,
+{
+    pub fn with_hasher(hash_builder: S) -> Interner<S> {
+        Interner {
+            ids: HashMap::with_hasher(hash_builder),
+            slots: Vec::new(),
+            free: Vec::new(),
+        }
+    }
+
+    /// Returns the symbol for the string, interning it if necessary, and
+    /// increments its reference count.
+    pub fn intern(&mut self, s: &str) -> Symbol {
+        if let Some(&sym) = self.ids.get(s) {
+            if let Some((_, ref mut count)) = self.slots[sym.0 as usize] {
+                *count += 1;
+            }
+            return sym;
+        }
+
+        let s: Arc<str> = Arc::from(s);
+        let sym = match self.free.pop() {
+            Some(sym) => {
+                self.slots[sym.0 as usize] = Some((s.clone(), 1));
+                sym
+            }
+            None => {
+                self.slots.push(Some((s.clone(), 1)));
+                Symbol(self.slots.len() as u32 - 1)
+            }
+        };
+        self.ids.insert(s, sym);
+        sym
+    }
+
+    /// Decrements the reference count of the symbol, freeing the string once
+    /// it's no longer referenced.
+    pub fn release(&mut self, sym: Symbol) {
+        let slot = &mut self.slots[sym.0 as usize];
+        let freed = match *slot {
+            Some((_, ref mut count)) => {
+                *count -= 1;
+                *count == 0
+            }
+            None => false,
+        };
+
+        if freed {
+            if let Some((s, _)) = slot.take() {
+                self.ids.remove(&s);
+            }
+            self.free.push(sym);
+        }
+    }
+
+    /// Returns the symbol for the string, if it is interned.
+    pub fn get(&self, s: &str) -> Option<Symbol> {
+        self.ids.get(s).cloned()
+    }
+
+    /// Returns the string of a symbol that hasn't been freed.
+    pub fn resolve(&self, sym: Symbol) -> &str {
+        match self.slots[sym.0 as usize] {
+            Some((ref s, _)) => s,
+            None => panic!("symbol {:?} has been freed", sym),
+        }
+    }
+}
+
+#[test]
+fn interner_test() {
+    use std::collections::hash_map::RandomState;
+
+    let mut interner = Interner::with_hasher(RandomState::new());
+
+    let alice = interner.intern("@alice:example.com");
+    let bob = interner.intern("@bob:example.com");
+    assert_ne!(alice, bob);
+    assert_eq!(interner.intern("@alice:example.com"), alice);
+
+    assert_eq!(interner.get("@bob:example.com"), Some(bob));
+    assert_eq!(interner.get("@carol:example.com"), None);
+    assert_eq!(interner.resolve(alice), "@alice:example.com");
+
+    // Alice was interned twice, so is only freed by the second release.
+    interner.release(alice);
+    assert_eq!(interner.get("@alice:example.com"), Some(alice));
+    interner.release(alice);
+    assert_eq!(interner.get("@alice:example.com"), None);
+
+    // The freed symbol is reused.
+    assert_eq!(interner.intern("@carol:example.com"), alice);
+    assert_eq!(interner.resolve(alice), "@carol:example.com");
+}
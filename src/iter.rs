@@ -0,0 +1,313 @@
+// Copyright 2018 New Vector Ltd
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not
+// use this file except in compliance with the License. You may obtain a copy of
+// the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS, WITHOUT
+// WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied. See the
+// License for the specific language governing permissions and limitations under
+// the License.
+
+//! Named iterator types for `StateMap`, needed for the `IntoIterator` impls.
+
+use std::collections::hash_map::{self, RandomState};
+use std::fmt::Debug;
+use std::hash::BuildHasher;
+
+use small_map::{self, SmallMap};
+use well_known;
+use {StateMap, TYPE_ALIASES, TYPE_MEMBERSHIP, TYPE_THIRD_PARTY_INVITE};
+
+/// An iterator over the entries of a `StateMap`, created by
+/// [`StateMap::iter`].
+pub struct Iter<'a, E: 'a, S: 'a = RandomState> {
+    well_known: well_known::Iter<'a, E>,
+    membership: hash_map::Iter<'a, String, E>,
+    aliases: hash_map::Iter<'a, String, E>,
+    invites: hash_map::Iter<'a, String, E>,
+    others: hash_map::Iter<'a, String, SmallMap<E, S>>,
+    current: Option<(&'a str, small_map::Iter<'a, E>)>,
+    remaining: usize,
+}
+
+impl<'a, E, S> Iter<'a, E, S>
+where
+    E: Debug + Clone,
+    S: BuildHasher + Clone,
+{
+    pub(crate) fn new(state_map: &'a StateMap<E, S>) -> Iter<'a, E, S> {
+        Iter {
+            well_known: state_map.well_known.iter(),
+            membership: state_map.membership.iter(),
+            aliases: state_map.aliases.iter(),
+            invites: state_map.invites.iter(),
+            others: state_map.others.iter(),
+            current: None,
+            remaining: state_map.len(),
+        }
+    }
+}
+
+impl<'a, E, S> Iterator for Iter<'a, E, S> {
+    type Item = ((&'a str, &'a str), &'a E);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let next = self.next_entry();
+        if next.is_some() {
+            self.remaining -= 1;
+        }
+        next
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<'a, E, S> ExactSizeIterator for Iter<'a, E, S> {}
+
+impl<'a, E, S> Iter<'a, E, S> {
+    fn next_entry(&mut self) -> Option<((&'a str, &'a str), &'a E)> {
+        if let Some((k, e)) = self.well_known.next() {
+            return Some(((k.as_str(), ""), e));
+        }
+        if let Some((u, e)) = self.membership.next() {
+            return Some(((TYPE_MEMBERSHIP, u), e));
+        }
+        if let Some((s, e)) = self.aliases.next() {
+            return Some(((TYPE_ALIASES, s), e));
+        }
+        if let Some((t, e)) = self.invites.next() {
+            return Some(((TYPE_THIRD_PARTY_INVITE, t), e));
+        }
+
+        loop {
+            if let Some((t, ref mut inner)) = self.current {
+                if let Some((s, e)) = inner.next() {
+                    return Some(((t, s), e));
+                }
+            }
+
+            let (t, h) = self.others.next()?;
+            self.current = Some((t, h.iter()));
+        }
+    }
+}
+
+/// An owning iterator over the entries of a `StateMap`, created by its
+/// `IntoIterator` impl.
+pub struct IntoIter<E, S = RandomState> {
+    well_known: well_known::IntoIter<E>,
+    membership: hash_map::IntoIter<String, E>,
+    aliases: hash_map::IntoIter<String, E>,
+    invites: hash_map::IntoIter<String, E>,
+    others: hash_map::IntoIter<String, SmallMap<E, S>>,
+    current: Option<(String, small_map::IntoIter<E>)>,
+    remaining: usize,
+}
+
+impl<E, S> IntoIter<E, S>
+where
+    E: Debug + Clone,
+    S: BuildHasher + Clone,
+{
+    pub(crate) fn new(state_map: StateMap<E, S>) -> IntoIter<E, S> {
+        let remaining = state_map.len();
+
+        IntoIter {
+            well_known: state_map.well_known.into_iter(),
+            membership: state_map.membership.into_iter(),
+            aliases: state_map.aliases.into_iter(),
+            invites: state_map.invites.into_iter(),
+            others: state_map.others.into_iter(),
+            current: None,
+            remaining,
+        }
+    }
+}
+
+impl<E, S> Iterator for IntoIter<E, S> {
+    type Item = ((String, String), E);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let next = self.next_entry();
+        if next.is_some() {
+            self.remaining -= 1;
+        }
+        next
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<E, S> ExactSizeIterator for IntoIter<E, S> {}
+
+impl<E, S> IntoIter<E, S> {
+    fn next_entry(&mut self) -> Option<((String, String), E)> {
+        if let Some((k, e)) = self.well_known.next() {
+            return Some(((k.as_str().into(), String::new()), e));
+        }
+        if let Some((u, e)) = self.membership.next() {
+            return Some(((TYPE_MEMBERSHIP.into(), u), e));
+        }
+        if let Some((s, e)) = self.aliases.next() {
+            return Some(((TYPE_ALIASES.into(), s), e));
+        }
+        if let Some((t, e)) = self.invites.next() {
+            return Some(((TYPE_THIRD_PARTY_INVITE.into(), t), e));
+        }
+
+        loop {
+            if let Some((ref t, ref mut inner)) = self.current {
+                if let Some((s, e)) = inner.next() {
+                    return Some(((t.clone(), s), e));
+                }
+            }
+
+            let (t, h) = self.others.next()?;
+            self.current = Some((t, h.into_iter()));
+        }
+    }
+}
+
+/// A draining iterator over the entries of a `StateMap`, created by
+/// [`StateMap::drain`].
+///
+/// The map is empty once the iterator is dropped, even if it was not fully
+/// consumed.
+pub struct Drain<'a, E: 'a, S: 'a = RandomState> {
+    well_known: well_known::Drain<'a, E>,
+    membership: hash_map::Drain<'a, String, E>,
+    aliases: hash_map::Drain<'a, String, E>,
+    invites: hash_map::Drain<'a, String, E>,
+    others: hash_map::Drain<'a, String, SmallMap<E, S>>,
+    current: Option<(String, small_map::IntoIter<E>)>,
+    remaining: usize,
+}
+
+impl<'a, E, S> Drain<'a, E, S>
+where
+    E: Debug + Clone,
+    S: BuildHasher + Clone,
+{
+    pub(crate) fn new(state_map: &'a mut StateMap<E, S>) -> Drain<'a, E, S> {
+        let remaining = state_map.len();
+        state_map.partial_types.clear();
+
+        Drain {
+            well_known: state_map.well_known.drain(),
+            membership: state_map.membership.drain(),
+            aliases: state_map.aliases.drain(),
+            invites: state_map.invites.drain(),
+            others: state_map.others.drain(),
+            current: None,
+            remaining,
+        }
+    }
+}
+
+impl<'a, E, S> Iterator for Drain<'a, E, S> {
+    type Item = ((String, String), E);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let next = self.next_entry();
+        if next.is_some() {
+            self.remaining -= 1;
+        }
+        next
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<'a, E, S> ExactSizeIterator for Drain<'a, E, S> {}
+
+impl<'a, E, S> Drain<'a, E, S> {
+    fn next_entry(&mut self) -> Option<((String, String), E)> {
+        if let Some((k, e)) = self.well_known.next() {
+            return Some(((k.as_str().into(), String::new()), e));
+        }
+        if let Some((u, e)) = self.membership.next() {
+            return Some(((TYPE_MEMBERSHIP.into(), u), e));
+        }
+        if let Some((s, e)) = self.aliases.next() {
+            return Some(((TYPE_ALIASES.into(), s), e));
+        }
+        if let Some((t, e)) = self.invites.next() {
+            return Some(((TYPE_THIRD_PARTY_INVITE.into(), t), e));
+        }
+
+        loop {
+            if let Some((ref t, ref mut inner)) = self.current {
+                if let Some((s, e)) = inner.next() {
+                    return Some(((t.clone(), s), e));
+                }
+            }
+
+            let (t, h) = self.others.next()?;
+            self.current = Some((t, h.into_iter()));
+        }
+    }
+}
+
+impl<E, S> IntoIterator for StateMap<E, S>
+where
+    E: Debug + Clone,
+    S: BuildHasher + Clone,
+{
+    type Item = ((String, String), E);
+    type IntoIter = IntoIter<E, S>;
+
+    fn into_iter(self) -> IntoIter<E, S> {
+        IntoIter::new(self)
+    }
+}
+
+impl<'a, E, S> IntoIterator for &'a StateMap<E, S>
+where
+    E: Debug + Clone,
+    S: BuildHasher + Clone,
+{
+    type Item = ((&'a str, &'a str), &'a E);
+    type IntoIter = Iter<'a, E, S>;
+
+    fn into_iter(self) -> Iter<'a, E, S> {
+        Iter::new(self)
+    }
+}
+
+#[test]
+fn into_iter_test() {
+    use std::collections::HashMap;
+    use TYPE_POWER_LEVELS;
+
+    let mut state_map = StateMap::new();
+
+    state_map.insert(TYPE_POWER_LEVELS, "", 1);
+    state_map.insert(TYPE_MEMBERSHIP, "@alice:example.com", 2);
+    state_map.insert(TYPE_ALIASES, "example.com", 3);
+    state_map.insert("test", "a", 4);
+    state_map.insert("test", "b", 5);
+
+    let expected: HashMap<_, _> = state_map
+        .iter()
+        .map(|((t, s), e)| ((t.to_string(), s.to_string()), *e))
+        .collect();
+
+    let iter = state_map.clone().into_iter();
+    assert_eq!(iter.len(), 5);
+    assert_eq!(iter.collect::<HashMap<_, _>>(), expected);
+
+    assert_eq!((&state_map).into_iter().count(), 5);
+
+    assert_eq!(state_map.drain().collect::<HashMap<_, _>>(), expected);
+    assert!(state_map.is_empty());
+}
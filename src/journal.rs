@@ -0,0 +1,376 @@
+// Copyright 2018 New Vector Ltd
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not
+// use this file except in compliance with the License. You may obtain a copy of
+// the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS, WITHOUT
+// WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied. See the
+// License for the specific language governing permissions and limitations under
+// the License.
+
+//! A `StateMap` wrapper that records and broadcasts every change made to it,
+//! e.g. for replicating state between processes.
+
+use std::borrow::Borrow;
+use std::collections::hash_map::RandomState;
+use std::fmt::{self, Debug};
+use std::hash::BuildHasher;
+use std::ops::Deref;
+use std::vec;
+
+use StateMap;
+
+/// A single change made to a [`JournaledStateMap`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StateChange<E> {
+    /// A new entry was added.
+    Inserted { key: (String, String), value: E },
+    /// The value of an existing entry was changed.
+    Replaced {
+        key: (String, String),
+        old: E,
+        new: E,
+    },
+    /// An entry was removed.
+    Removed { key: (String, String), old: E },
+}
+
+impl<E> StateChange<E> {
+    fn new(t: &str, s: &str, old: Option<E>, new: Option<E>) -> Option<StateChange<E>> {
+        let key = (t.to_string(), s.to_string());
+        match (old, new) {
+            (None, Some(value)) => Some(StateChange::Inserted { key, value }),
+            (Some(old), Some(new)) => Some(StateChange::Replaced { key, old, new }),
+            (Some(old), None) => Some(StateChange::Removed { key, old }),
+            (None, None) => None,
+        }
+    }
+
+    /// The `(type, state_key)` of the entry that changed.
+    pub fn key(&self) -> (&str, &str) {
+        let (t, s) = match *self {
+            StateChange::Inserted { ref key, .. }
+            | StateChange::Replaced { ref key, .. }
+            | StateChange::Removed { ref key, .. } => key,
+        };
+        (t, s)
+    }
+}
+
+/// Identifies a callback registered with [`JournaledStateMap::subscribe`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SubscriptionId(u64);
+
+type ChangeFilter = Box<dyn Fn(&str, &str) -> bool + Send>;
+type ChangeCallback<E> = Box<dyn FnMut(&StateChange<E>) + Send>;
+
+struct Subscription<E> {
+    id: SubscriptionId,
+    filter: ChangeFilter,
+    callback: ChangeCallback<E>,
+}
+
+/// The journal and subscriptions of a [`JournaledStateMap`].
+struct Recorder<E> {
+    journaling: bool,
+    journal: Vec<StateChange<E>>,
+    subscriptions: Vec<Subscription<E>>,
+    next_subscription_id: u64,
+}
+
+impl<E: Clone> Recorder<E> {
+    /// Whether a change to the given key needs to be recorded at all.
+    fn wants(&self, t: &str, s: &str) -> bool {
+        self.journaling || self.subscriptions.iter().any(|sub| (sub.filter)(t, s))
+    }
+
+    /// Records a change in the journal (if enabled) and notifies any matching
+    /// subscriptions.
+    fn record(&mut self, t: &str, s: &str, old: Option<E>, new: Option<E>) {
+        let change = match StateChange::new(t, s, old, new) {
+            Some(change) => change,
+            None => return,
+        };
+
+        for sub in &mut self.subscriptions {
+            if (sub.filter)(t, s) {
+                (sub.callback)(&change);
+            }
+        }
+
+        if self.journaling {
+            self.journal.push(change);
+        }
+    }
+}
+
+/// Wraps a `StateMap` so that every insert, replace and remove made through
+/// it can be recorded in a journal and passed to subscribed callbacks.
+///
+/// Reads go straight to the underlying map, as the wrapper derefs to it.
+pub struct JournaledStateMap<E: Debug + Clone, S: BuildHasher + Clone = RandomState> {
+    state_map: StateMap<E, S>,
+    recorder: Recorder<E>,
+}
+
+impl<E, S> StateMap<E, S>
+where
+    E: Debug + Clone,
+    S: BuildHasher + Clone,
+{
+    /// Wraps the map so that changes to it can be journaled and subscribed to.
+    /// Journaling starts off enabled.
+    pub fn into_journaled(self) -> JournaledStateMap<E, S> {
+        JournaledStateMap {
+            state_map: self,
+            recorder: Recorder {
+                journaling: true,
+                journal: Vec::new(),
+                subscriptions: Vec::new(),
+                next_subscription_id: 0,
+            },
+        }
+    }
+}
+
+impl<E, S> JournaledStateMap<E, S>
+where
+    E: Debug + Clone,
+    S: BuildHasher + Clone,
+{
+    /// Enables or disables recording changes in the journal. Disabling it
+    /// discards any changes that haven't been drained.
+    ///
+    /// Subscriptions are notified of changes either way.
+    pub fn set_journaling(&mut self, enabled: bool) {
+        self.recorder.journaling = enabled;
+        if !enabled {
+            self.recorder.journal = Vec::new();
+        }
+    }
+
+    /// Removes and returns all changes recorded since the journal was last
+    /// drained, oldest first.
+    pub fn drain_journal(&mut self) -> vec::Drain<'_, StateChange<E>> {
+        self.recorder.journal.drain(..)
+    }
+
+    /// Registers `callback` to be called with every change to a key for which
+    /// `filter` returns true.
+    pub fn subscribe<F, C>(&mut self, filter: F, callback: C) -> SubscriptionId
+    where
+        F: Fn(&str, &str) -> bool + Send + 'static,
+        C: FnMut(&StateChange<E>) + Send + 'static,
+    {
+        let id = SubscriptionId(self.recorder.next_subscription_id);
+        self.recorder.next_subscription_id += 1;
+
+        self.recorder.subscriptions.push(Subscription {
+            id,
+            filter: Box::new(filter),
+            callback: Box::new(callback),
+        });
+
+        id
+    }
+
+    /// Removes a callback registered with [`JournaledStateMap::subscribe`].
+    pub fn unsubscribe(&mut self, id: SubscriptionId) {
+        self.recorder.subscriptions.retain(|sub| sub.id != id);
+    }
+
+    /// Unwraps the underlying map, discarding the journal and subscriptions.
+    pub fn into_inner(self) -> StateMap<E, S> {
+        self.state_map
+    }
+
+    /// Inserts the entry into the map, as [`StateMap::insert`].
+    pub fn insert(&mut self, t: &str, s: &str, value: E) -> Option<E> {
+        if !self.recorder.wants(t, s) {
+            return self.state_map.insert(t, s, value);
+        }
+
+        let old = self.state_map.insert(t, s, value.clone());
+        self.recorder.record(t, s, old.clone(), Some(value));
+        old
+    }
+
+    /// Removes the entry from the map, as [`StateMap::remove`].
+    pub fn remove(&mut self, t: &str, s: &str) -> Option<E> {
+        let old = self.state_map.remove(t, s);
+        if old.is_some() && self.recorder.wants(t, s) {
+            self.recorder.record(t, s, old.clone(), None);
+        }
+        old
+    }
+
+    /// Updates the value of an existing entry in place, returning false if
+    /// there is no such entry.
+    ///
+    /// This stands in for [`StateMap::get_mut`], whose changes can't be
+    /// journaled.
+    pub fn update<F>(&mut self, t: &str, s: &str, f: F) -> bool
+    where
+        F: FnOnce(&mut E),
+    {
+        let wants = self.recorder.wants(t, s);
+
+        let e = match self.state_map.get_mut(t, s) {
+            Some(e) => e,
+            None => return false,
+        };
+
+        if wants {
+            let old = e.clone();
+            f(e);
+            self.recorder.record(t, s, Some(old), Some(e.clone()));
+        } else {
+            f(e);
+        }
+
+        true
+    }
+
+    /// Retains only the entries for which the predicate returns true, as
+    /// [`StateMap::retain`]. Unlike that the predicate can't change the
+    /// values, use [`JournaledStateMap::update`] for that so the change is
+    /// journaled.
+    pub fn retain<F>(&mut self, mut f: F)
+    where
+        F: FnMut(&str, &str, &E) -> bool,
+    {
+        let recorder = &mut self.recorder;
+
+        self.state_map.retain(|t, s, e| {
+            let keep = f(t, s, e);
+            if !keep && recorder.wants(t, s) {
+                recorder.record(t, s, Some(e.clone()), None);
+            }
+            keep
+        });
+    }
+}
+
+impl<E, S> JournaledStateMap<E, S>
+where
+    E: Debug + Clone + PartialEq,
+    S: BuildHasher + Clone,
+{
+    /// Inserts or removes the entry, as [`StateMap::add_or_remove`].
+    pub fn add_or_remove<F>(&mut self, t: &str, s: &str, v: F) -> Option<E>
+    where
+        F: Borrow<E>,
+    {
+        if !self.recorder.wants(t, s) {
+            return self.state_map.add_or_remove(t, s, v);
+        }
+
+        let existed = self.state_map.contains_key(t, s);
+        let removed = self.state_map.add_or_remove(t, s, v.borrow());
+
+        if removed.is_some() {
+            self.recorder.record(t, s, removed.clone(), None);
+        } else if !existed {
+            self.recorder.record(t, s, None, Some(v.borrow().clone()));
+        }
+
+        removed
+    }
+}
+
+impl<E, S> Deref for JournaledStateMap<E, S>
+where
+    E: Debug + Clone,
+    S: BuildHasher + Clone,
+{
+    type Target = StateMap<E, S>;
+
+    fn deref(&self) -> &StateMap<E, S> {
+        &self.state_map
+    }
+}
+
+impl<E, S> Debug for JournaledStateMap<E, S>
+where
+    E: Debug + Clone,
+    S: BuildHasher + Clone + Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("JournaledStateMap")
+            .field("state_map", &self.state_map)
+            .field("journaling", &self.recorder.journaling)
+            .field("journal", &self.recorder.journal)
+            .field("subscriptions", &self.recorder.subscriptions.len())
+            .finish()
+    }
+}
+
+#[test]
+fn journal_test() {
+    use std::sync::{Arc, Mutex};
+    use {TYPE_MEMBERSHIP, TYPE_POWER_LEVELS};
+
+    let mut state_map = StateMap::new().into_journaled();
+
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let seen_clone = seen.clone();
+    state_map.subscribe(
+        |t, _| t == TYPE_POWER_LEVELS,
+        move |change: &StateChange<i32>| seen_clone.lock().unwrap().push(change.clone()),
+    );
+
+    state_map.insert(TYPE_POWER_LEVELS, "", 1);
+    state_map.insert(TYPE_POWER_LEVELS, "", 2);
+    state_map.insert(TYPE_MEMBERSHIP, "@alice:example.com", 3);
+    state_map.update(TYPE_MEMBERSHIP, "@alice:example.com", |e| *e += 1);
+    state_map.remove(TYPE_MEMBERSHIP, "@alice:example.com");
+    state_map.remove(TYPE_MEMBERSHIP, "@bob:example.com");
+
+    let key = |t: &str, s: &str| (t.to_string(), s.to_string());
+    let journal: Vec<_> = state_map.drain_journal().collect();
+    assert_eq!(
+        journal,
+        vec![
+            StateChange::Inserted {
+                key: key(TYPE_POWER_LEVELS, ""),
+                value: 1,
+            },
+            StateChange::Replaced {
+                key: key(TYPE_POWER_LEVELS, ""),
+                old: 1,
+                new: 2,
+            },
+            StateChange::Inserted {
+                key: key(TYPE_MEMBERSHIP, "@alice:example.com"),
+                value: 3,
+            },
+            StateChange::Replaced {
+                key: key(TYPE_MEMBERSHIP, "@alice:example.com"),
+                old: 3,
+                new: 4,
+            },
+            StateChange::Removed {
+                key: key(TYPE_MEMBERSHIP, "@alice:example.com"),
+                old: 4,
+            },
+        ]
+    );
+    assert_eq!(state_map.drain_journal().count(), 0);
+
+    assert_eq!(seen.lock().unwrap().len(), 2);
+    assert!(seen
+        .lock()
+        .unwrap()
+        .iter()
+        .all(|c| c.key() == (TYPE_POWER_LEVELS, "")));
+
+    state_map.set_journaling(false);
+    state_map.add_or_remove(TYPE_POWER_LEVELS, "", 5);
+    assert_eq!(state_map.drain_journal().count(), 0);
+    assert_eq!(seen.lock().unwrap().len(), 3);
+    assert_eq!(state_map.len(), 0);
+}
@@ -0,0 +1,229 @@
+// Copyright 2018 New Vector Ltd
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not
+// use this file except in compliance with the License. You may obtain a copy of
+// the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS, WITHOUT
+// WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied. See the
+// License for the specific language governing permissions and limitations under
+// the License.
+
+//! Typed state keys, which record which of the map's buckets an entry belongs
+//! to so that callers can match on it rather than comparing type strings.
+
+use std::fmt::Debug;
+use std::hash::BuildHasher;
+
+use {StateMap, WellKnownEmptyKeys, TYPE_ALIASES, TYPE_MEMBERSHIP, TYPE_THIRD_PARTY_INVITE};
+
+/// A borrowed `(type, state_key)` pair, classified by bucket.
+///
+/// Keys should be created with [`StateKeyRef::new`], so that e.g. a
+/// membership key is never represented as `Other`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum StateKeyRef<'a> {
+    /// One of the well known types with an empty state key.
+    WellKnown(WellKnownEmptyKeys),
+    /// An `m.room.member` entry, holding the user ID.
+    Member(&'a str),
+    /// An `m.room.aliases` entry, holding the server name.
+    Aliases(&'a str),
+    /// An `m.room.third_party_invite` entry, holding the token.
+    ThirdPartyInvite(&'a str),
+    /// Any other type and state key.
+    Other(&'a str, &'a str),
+}
+
+/// An owned `(type, state_key)` pair, classified by bucket. See
+/// [`StateKeyRef`].
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum StateKey {
+    WellKnown(WellKnownEmptyKeys),
+    Member(String),
+    Aliases(String),
+    ThirdPartyInvite(String),
+    Other(String, String),
+}
+
+impl<'a> StateKeyRef<'a> {
+    /// Classifies the given type and state key.
+    pub fn new(t: &'a str, s: &'a str) -> StateKeyRef<'a> {
+        if s.is_empty() {
+            if let Some(key) = WellKnownEmptyKeys::from_str(t) {
+                return StateKeyRef::WellKnown(key);
+            }
+        }
+
+        match t {
+            TYPE_MEMBERSHIP => StateKeyRef::Member(s),
+            TYPE_ALIASES => StateKeyRef::Aliases(s),
+            TYPE_THIRD_PARTY_INVITE => StateKeyRef::ThirdPartyInvite(s),
+            _ => StateKeyRef::Other(t, s),
+        }
+    }
+
+    /// The event type of the key.
+    pub fn event_type(self) -> &'a str {
+        self.as_tuple().0
+    }
+
+    /// The state key of the key.
+    pub fn state_key(self) -> &'a str {
+        self.as_tuple().1
+    }
+
+    /// Returns the key as a `(type, state_key)` pair.
+    pub fn as_tuple(self) -> (&'a str, &'a str) {
+        match self {
+            StateKeyRef::WellKnown(k) => (k.as_str(), ""),
+            StateKeyRef::Member(u) => (TYPE_MEMBERSHIP, u),
+            StateKeyRef::Aliases(s) => (TYPE_ALIASES, s),
+            StateKeyRef::ThirdPartyInvite(t) => (TYPE_THIRD_PARTY_INVITE, t),
+            StateKeyRef::Other(t, s) => (t, s),
+        }
+    }
+
+    /// Copies the key into an owned [`StateKey`].
+    pub fn into_owned(self) -> StateKey {
+        match self {
+            StateKeyRef::WellKnown(k) => StateKey::WellKnown(k),
+            StateKeyRef::Member(u) => StateKey::Member(u.to_string()),
+            StateKeyRef::Aliases(s) => StateKey::Aliases(s.to_string()),
+            StateKeyRef::ThirdPartyInvite(t) => StateKey::ThirdPartyInvite(t.to_string()),
+            StateKeyRef::Other(t, s) => StateKey::Other(t.to_string(), s.to_string()),
+        }
+    }
+}
+
+impl StateKey {
+    /// Classifies the given type and state key.
+    pub fn new(t: &str, s: &str) -> StateKey {
+        StateKeyRef::new(t, s).into_owned()
+    }
+
+    /// Borrows the key as a [`StateKeyRef`].
+    pub fn borrow_ref(&self) -> StateKeyRef<'_> {
+        match *self {
+            StateKey::WellKnown(k) => StateKeyRef::WellKnown(k),
+            StateKey::Member(ref u) => StateKeyRef::Member(u),
+            StateKey::Aliases(ref s) => StateKeyRef::Aliases(s),
+            StateKey::ThirdPartyInvite(ref t) => StateKeyRef::ThirdPartyInvite(t),
+            StateKey::Other(ref t, ref s) => StateKeyRef::Other(t, s),
+        }
+    }
+
+    /// Returns the key as a `(type, state_key)` pair.
+    pub fn as_tuple(&self) -> (&str, &str) {
+        self.borrow_ref().as_tuple()
+    }
+}
+
+impl<'a> From<(&'a str, &'a str)> for StateKeyRef<'a> {
+    fn from((t, s): (&'a str, &'a str)) -> StateKeyRef<'a> {
+        StateKeyRef::new(t, s)
+    }
+}
+
+impl<'a> From<(&'a str, &'a str)> for StateKey {
+    fn from((t, s): (&'a str, &'a str)) -> StateKey {
+        StateKey::new(t, s)
+    }
+}
+
+impl<'a> From<StateKeyRef<'a>> for StateKey {
+    fn from(key: StateKeyRef<'a>) -> StateKey {
+        key.into_owned()
+    }
+}
+
+impl<E, S> StateMap<E, S>
+where
+    E: Debug + Clone,
+    S: BuildHasher + Clone,
+{
+    /// Returns an iterator over all keys and values in the state map, with
+    /// the keys classified by the bucket they're stored in.
+    pub fn iter_typed(&self) -> impl Iterator<Item = (StateKeyRef<'_>, &E)> {
+        let w = self
+            .well_known
+            .iter()
+            .map(|(k, e)| (StateKeyRef::WellKnown(k), e));
+
+        let m = self
+            .membership
+            .iter()
+            .map(|(u, e)| (StateKeyRef::Member(u), e));
+
+        let a = self
+            .aliases
+            .iter()
+            .map(|(s, e)| (StateKeyRef::Aliases(s), e));
+
+        let i = self
+            .invites
+            .iter()
+            .map(|(t, e)| (StateKeyRef::ThirdPartyInvite(t), e));
+
+        let o = self
+            .others
+            .iter()
+            .flat_map(|(t, h)| h.iter().map(move |(s, e)| (StateKeyRef::Other(t, s), e)));
+
+        w.chain(m).chain(a).chain(i).chain(o)
+    }
+
+    /// Gets the value for a typed key.
+    pub fn get_typed(&self, key: StateKeyRef<'_>) -> Option<&E> {
+        match key {
+            StateKeyRef::WellKnown(k) => self.well_known.get(k),
+            StateKeyRef::Member(u) => self.membership.get(u),
+            StateKeyRef::Aliases(s) => self.aliases.get(s),
+            StateKeyRef::ThirdPartyInvite(t) => self.invites.get(t),
+            StateKeyRef::Other(t, s) => self.others.get(t).and_then(|h| h.get(s)),
+        }
+    }
+}
+
+#[test]
+fn iter_typed_test() {
+    use std::collections::HashMap;
+    use {TYPE_JOIN_RULES, TYPE_POWER_LEVELS};
+
+    let mut state_map = StateMap::new();
+    state_map.insert(TYPE_POWER_LEVELS, "", 1);
+    state_map.insert(TYPE_JOIN_RULES, "not_empty", 2);
+    state_map.insert(TYPE_MEMBERSHIP, "@alice:example.com", 3);
+    state_map.insert(TYPE_ALIASES, "example.com", 4);
+    state_map.insert("test", "a", 5);
+
+    let mut members = 0;
+    for (key, e) in state_map.iter_typed() {
+        if let StateKeyRef::Member(user) = key {
+            assert_eq!(user, "@alice:example.com");
+            members += 1;
+        }
+        assert_eq!(StateKeyRef::new(key.event_type(), key.state_key()), key);
+        assert_eq!(state_map.get(key.event_type(), key.state_key()), Some(e));
+        assert_eq!(state_map.get_typed(key), Some(e));
+    }
+    assert_eq!(members, 1);
+
+    let owned: HashMap<StateKey, i32> = state_map
+        .iter_typed()
+        .map(|(k, e)| (k.into_owned(), *e))
+        .collect();
+    assert_eq!(owned.len(), 5);
+    assert_eq!(
+        owned[&StateKey::WellKnown(WellKnownEmptyKeys::PowerLevels)],
+        1
+    );
+    assert_eq!(owned[&StateKey::new(TYPE_JOIN_RULES, "not_empty")], 2);
+    assert_eq!(
+        StateKey::new(TYPE_JOIN_RULES, "not_empty").as_tuple(),
+        (TYPE_JOIN_RULES, "not_empty")
+    );
+}
@@ -20,10 +20,69 @@
 //! map are well known, so we can specialize storage to reduce memory usage
 //! compared to naively storing a map of string tuples.
 
+// `WellKnownEmptyKeys::from_str` and `iter_test` predate these lints.
+#![allow(clippy::should_implement_trait, clippy::explicit_counter_loop)]
+
+#[cfg(feature = "python")]
+extern crate pyo3;
+#[cfg(feature = "rayon")]
+extern crate rayon;
+
 use std::borrow::Borrow;
-use std::collections::{hash_map, HashMap};
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::convert::Infallible;
 use std::fmt::Debug;
+use std::hash::{BuildHasher, Hash};
 use std::iter::FromIterator;
+use std::mem;
+
+use small_map::SmallMap;
+use well_known::WellKnownMap;
+
+mod cache;
+#[cfg(feature = "capi")]
+pub mod capi;
+mod display_name;
+mod heap_size;
+mod interner;
+mod iter;
+mod journal;
+mod key;
+mod name_index;
+mod overlay;
+#[cfg(feature = "rayon")]
+mod parallel;
+mod partial;
+#[cfg(feature = "python")]
+mod python;
+mod reverse_index;
+mod room_index;
+mod set_ops;
+mod small_map;
+mod stripped;
+mod summary;
+mod transaction;
+mod validate;
+mod well_known;
+
+pub use cache::{StateMapCache, StateMapCacheEntry};
+pub use display_name::{member_display_name, room_display_name, RoomNameEvent};
+pub use heap_size::{HeapSize, HeapSizeBreakdown};
+pub use iter::{Drain, IntoIter, Iter};
+pub use journal::{JournaledStateMap, StateChange, SubscriptionId};
+pub use key::{StateKey, StateKeyRef};
+pub use name_index::DisplayNameIndex;
+pub use overlay::{StateDelta, StateMapOverlay};
+pub use partial::StateLookup;
+#[cfg(feature = "python")]
+pub use python::PyStateMap;
+pub use reverse_index::IndexedStateMap;
+pub use room_index::RoomStateIndex;
+pub use stripped::{StrippedEvent, StrippedStateConfig, STRIPPED_STATE_TYPES};
+pub use summary::{room_summary, Membership, MembershipEvent, RoomSummary, MAX_HEROES};
+pub use transaction::Transaction;
+pub use validate::{StateMapError, MAX_KEY_LENGTH};
 
 /// The creation event type - `m.room.create`
 pub const TYPE_CREATE: &str = "m.room.create";
@@ -57,7 +116,9 @@ pub const TYPE_THIRD_PARTY_INVITE: &str = "m.room.third_party_invite";
 
 /// List of event types that are commonly used for state with empty state
 /// keys.
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+///
+/// Keys are ordered by their position in [`WellKnownEmptyKeys::ALL`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub enum WellKnownEmptyKeys {
     /// The creation event type - `m.room.create`
     Create,
@@ -84,6 +145,26 @@ pub enum WellKnownEmptyKeys {
 }
 
 impl WellKnownEmptyKeys {
+    /// All of the well known keys, in order.
+    pub const ALL: [WellKnownEmptyKeys; 11] = [
+        WellKnownEmptyKeys::Create,
+        WellKnownEmptyKeys::PowerLevels,
+        WellKnownEmptyKeys::JoinRules,
+        WellKnownEmptyKeys::HistoryVisibility,
+        WellKnownEmptyKeys::Name,
+        WellKnownEmptyKeys::Topic,
+        WellKnownEmptyKeys::Avatar,
+        WellKnownEmptyKeys::GuestAccess,
+        WellKnownEmptyKeys::CanonicalAliases,
+        WellKnownEmptyKeys::RelatedGroups,
+        WellKnownEmptyKeys::Encryption,
+    ];
+
+    /// The position of the key in [`WellKnownEmptyKeys::ALL`].
+    pub fn index(self) -> usize {
+        self as usize
+    }
+
     /// Gets the event type as a string
     pub fn as_str(self) -> &'static str {
         match self {
@@ -121,13 +202,19 @@ impl WellKnownEmptyKeys {
 }
 
 /// A specialised container for storing state mapping.
-#[derive(Debug, Clone, Default, PartialEq, Eq)]
-pub struct StateMap<E: Debug + Clone> {
-    well_known: HashMap<WellKnownEmptyKeys, E>,
-    membership: HashMap<String, E>,
-    aliases: HashMap<String, E>,
-    invites: HashMap<String, E>,
-    others: HashMap<String, HashMap<String, E>>,
+///
+/// The hasher used by the internal maps defaults to `RandomState`, but can be
+/// swapped for any `BuildHasher` with [`StateMap::with_hasher`].
+#[derive(Debug, Clone, Default)]
+pub struct StateMap<E: Debug + Clone, S = RandomState> {
+    well_known: WellKnownMap<E>,
+    membership: HashMap<String, E, S>,
+    aliases: HashMap<String, E, S>,
+    invites: HashMap<String, E, S>,
+    others: HashMap<String, SmallMap<E, S>, S>,
+    /// Types for which only some of the state is known, kept sorted. See
+    /// [`StateMap::mark_partial`].
+    partial_types: Vec<String>,
 }
 
 impl<E> StateMap<E>
@@ -136,16 +223,47 @@ where
 {
     pub fn new() -> StateMap<E> {
         StateMap {
-            well_known: HashMap::new(),
+            well_known: WellKnownMap::new(),
             membership: HashMap::new(),
             aliases: HashMap::new(),
             invites: HashMap::new(),
             others: HashMap::new(),
+            partial_types: Vec::new(),
+        }
+    }
+}
+
+impl<E, S> StateMap<E, S>
+where
+    E: Debug + Clone,
+    S: BuildHasher + Clone,
+{
+    /// Creates an empty state map which will use the given hash builder to
+    /// hash keys.
+    pub fn with_hasher(hash_builder: S) -> StateMap<E, S> {
+        StateMap {
+            well_known: WellKnownMap::new(),
+            membership: HashMap::with_hasher(hash_builder.clone()),
+            aliases: HashMap::with_hasher(hash_builder.clone()),
+            invites: HashMap::with_hasher(hash_builder.clone()),
+            others: HashMap::with_hasher(hash_builder),
+            partial_types: Vec::new(),
         }
     }
 
+    /// Returns a reference to the map's `BuildHasher`.
+    pub fn hasher(&self) -> &S {
+        self.others.hasher()
+    }
+
     pub fn get_well_known(&self, key: WellKnownEmptyKeys) -> Option<&E> {
-        self.well_known.get(&key)
+        self.well_known.get(key)
+    }
+
+    /// Returns an iterator over the entries with well known types and empty
+    /// state keys, in key order.
+    pub fn iter_well_known(&self) -> impl Iterator<Item = (WellKnownEmptyKeys, &E)> {
+        self.well_known.iter()
     }
 
     pub fn get_aliases(&self, server: &str) -> Option<&E> {
@@ -161,13 +279,13 @@ where
     }
 
     pub fn get(&self, t: &str, s: &str) -> Option<&E> {
-        if s == "" {
+        if s.is_empty() {
             if let Some(key) = WellKnownEmptyKeys::from_str(t) {
                 return self.get_well_known(key);
             }
         }
 
-        match (t.borrow(), s.borrow()) {
+        match (t, s) {
             (TYPE_MEMBERSHIP, user) => self.get_membership(user),
             (TYPE_ALIASES, server) => self.get_aliases(server),
             (TYPE_THIRD_PARTY_INVITE, token) => self.get_third_party_invites(token),
@@ -177,13 +295,13 @@ where
     }
 
     pub fn get_mut(&mut self, t: &str, s: &str) -> Option<&mut E> {
-        if s == "" {
+        if s.is_empty() {
             if let Some(key) = WellKnownEmptyKeys::from_str(t) {
-                return self.well_known.get_mut(&key);
+                return self.well_known.get_mut(key);
             }
         }
 
-        match (t.borrow(), s.borrow()) {
+        match (t, s) {
             (TYPE_MEMBERSHIP, user) => self.membership.get_mut(user),
             (TYPE_ALIASES, server) => self.aliases.get_mut(server),
             (TYPE_THIRD_PARTY_INVITE, token) => self.invites.get_mut(token),
@@ -196,31 +314,180 @@ where
         self.well_known.insert(k, value);
     }
 
-    pub fn insert(&mut self, t: &str, s: &str, value: E) {
-        if s == "" {
+    /// Inserts the entry into the map, returning the previous value if there
+    /// was one.
+    ///
+    /// The keys are only copied into new `String`s if the entry doesn't
+    /// already exist.
+    pub fn insert(&mut self, t: &str, s: &str, value: E) -> Option<E> {
+        if s.is_empty() {
             if let Some(key) = WellKnownEmptyKeys::from_str(t) {
-                self.well_known.insert(key, value);
-                return;
+                return self.well_known.insert(key, value);
             }
         }
 
         match (t, s) {
-            (TYPE_MEMBERSHIP, user) => self.membership.insert(user.into(), value),
-            (TYPE_ALIASES, server) => self.aliases.insert(server.into(), value),
-            (TYPE_THIRD_PARTY_INVITE, token) => self.invites.insert(token.into(), value),
+            (TYPE_MEMBERSHIP, user) => insert_in_bucket(&mut self.membership, user, value),
+            (TYPE_ALIASES, server) => insert_in_bucket(&mut self.aliases, server, value),
+            (TYPE_THIRD_PARTY_INVITE, token) => insert_in_bucket(&mut self.invites, token, value),
+
+            (t, s) => {
+                // The type maps don't store a hasher, so pass them ours in case
+                // they outgrow a list.
+                let hash_builder = self.others.hasher().clone();
+                if let Some(h) = self.others.get_mut(t) {
+                    return h.insert_str(s, value, &hash_builder);
+                }
+
+                self.others
+                    .insert(t.into(), SmallMap::with_entry(s.into(), value));
+                None
+            }
+        }
+    }
+
+    /// Inserts the entry into the map as [`StateMap::insert`], taking
+    /// ownership of the keys so that they don't need to be copied.
+    pub fn insert_owned(&mut self, t: String, s: String, value: E) -> Option<E> {
+        if s.is_empty() {
+            if let Some(key) = WellKnownEmptyKeys::from_str(&t) {
+                return self.well_known.insert(key, value);
+            }
+        }
 
-            (t, s) => self
-                .others
-                .entry(t.into())
-                .or_insert_with(HashMap::new)
-                .insert(s.into(), value),
-        };
+        match &t as &str {
+            TYPE_MEMBERSHIP => self.membership.insert(s, value),
+            TYPE_ALIASES => self.aliases.insert(s, value),
+            TYPE_THIRD_PARTY_INVITE => self.invites.insert(s, value),
+
+            _ => {
+                let hash_builder = self.others.hasher().clone();
+                self.others
+                    .entry(t)
+                    .or_insert_with(SmallMap::new)
+                    .insert(s, value, &hash_builder)
+            }
+        }
+    }
+
+    /// Removes the entry from the map, returning its value if it existed.
+    pub fn remove(&mut self, t: &str, s: &str) -> Option<E> {
+        if s.is_empty() {
+            if let Some(key) = WellKnownEmptyKeys::from_str(t) {
+                return self.well_known.remove(key);
+            }
+        }
+
+        match (t, s) {
+            (TYPE_MEMBERSHIP, user) => self.membership.remove(user),
+            (TYPE_ALIASES, server) => self.aliases.remove(server),
+            (TYPE_THIRD_PARTY_INVITE, token) => self.invites.remove(token),
+
+            (t, s) => {
+                let (value, is_empty) = {
+                    let h = self.others.get_mut(t)?;
+                    (h.remove(s), h.is_empty())
+                };
+                if is_empty {
+                    self.others.remove(t);
+                }
+                value
+            }
+        }
     }
 
     pub fn contains_key(&self, t: &str, s: &str) -> bool {
         self.get(t, s).is_some()
     }
 
+    /// Retains only the entries for which the predicate returns true, passing
+    /// in the type, state key and a mutable reference to the value.
+    ///
+    /// Any per type maps for uncommon types that end up empty are removed.
+    pub fn retain<F>(&mut self, mut f: F)
+    where
+        F: FnMut(&str, &str, &mut E) -> bool,
+    {
+        self.well_known.retain(|k, e| f(k.as_str(), "", e));
+        self.membership.retain(|u, e| f(TYPE_MEMBERSHIP, u, e));
+        self.aliases.retain(|s, e| f(TYPE_ALIASES, s, e));
+        self.invites.retain(|t, e| f(TYPE_THIRD_PARTY_INVITE, t, e));
+
+        self.others.retain(|t, h| {
+            h.retain(|s, e| f(t, s, e));
+            !h.is_empty()
+        });
+    }
+
+    /// Retains only the entries of the given type for which the predicate
+    /// returns true, passing in the state key and a mutable reference to the
+    /// value. Entries of other types are left untouched.
+    pub fn retain_type<F>(&mut self, t: &str, mut f: F)
+    where
+        F: FnMut(&str, &mut E) -> bool,
+    {
+        match t {
+            TYPE_MEMBERSHIP => self.membership.retain(|u, e| f(u, e)),
+            TYPE_ALIASES => self.aliases.retain(|s, e| f(s, e)),
+            TYPE_THIRD_PARTY_INVITE => self.invites.retain(|t, e| f(t, e)),
+
+            t => {
+                if let Some(key) = WellKnownEmptyKeys::from_str(t) {
+                    let keep = match self.well_known.get_mut(key) {
+                        Some(e) => f("", e),
+                        None => true,
+                    };
+                    if !keep {
+                        self.well_known.remove(key);
+                    }
+                }
+
+                let is_empty = match self.others.get_mut(t) {
+                    Some(h) => {
+                        h.retain(|s, e| f(s, e));
+                        h.is_empty()
+                    }
+                    None => false,
+                };
+                if is_empty {
+                    self.others.remove(t);
+                }
+            }
+        }
+    }
+
+    /// Converts the map into one with a different value type by applying `f`
+    /// to each entry, passing in the type, state key and value.
+    ///
+    /// The key strings are moved into the new map rather than reallocated.
+    pub fn map_values<F, M>(self, mut f: M) -> StateMap<F, S>
+    where
+        F: Debug + Clone,
+        M: FnMut(&str, &str, E) -> F,
+    {
+        match self.try_map_values(|t, s, e| Ok::<F, Infallible>(f(t, s, e))) {
+            Ok(state_map) => state_map,
+            Err(never) => match never {},
+        }
+    }
+
+    /// Like [`StateMap::map_values`], except that `f` may fail, in which case
+    /// the first error is returned and the remaining entries are dropped.
+    pub fn try_map_values<F, Err, M>(self, mut f: M) -> Result<StateMap<F, S>, Err>
+    where
+        F: Debug + Clone,
+        M: FnMut(&str, &str, E) -> Result<F, Err>,
+    {
+        Ok(StateMap {
+            well_known: self.well_known.try_map(|k, e| f(k.as_str(), "", e))?,
+            membership: try_map_bucket(self.membership, |u, e| f(TYPE_MEMBERSHIP, u, e))?,
+            aliases: try_map_bucket(self.aliases, |s, e| f(TYPE_ALIASES, s, e))?,
+            invites: try_map_bucket(self.invites, |t, e| f(TYPE_THIRD_PARTY_INVITE, t, e))?,
+            others: try_map_bucket(self.others, |t, h| h.try_map(|s, e| f(t, s, e)))?,
+            partial_types: self.partial_types,
+        })
+    }
+
     /// Returns an iterator over all keys in the state map
     pub fn keys(&self) -> impl Iterator<Item = (&str, &str)> {
         let w = self.well_known.keys().map(|k| (k.as_str(), ""));
@@ -243,28 +510,37 @@ where
     }
 
     /// Returns an iterator over all keys and values in the state map
-    pub fn iter(&self) -> impl Iterator<Item = ((&str, &str), &E)> {
-        let w = self.well_known.iter().map(|(k, e)| ((k.as_str(), ""), e));
+    pub fn iter(&self) -> Iter<'_, E, S> {
+        Iter::new(self)
+    }
+
+    /// Returns an iterator over all keys and values in the state map, with
+    /// mutable references to the values
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = ((&str, &str), &mut E)> {
+        let w = self
+            .well_known
+            .iter_mut()
+            .map(|(k, e)| ((k.as_str(), ""), e));
 
         let m = self
             .membership
-            .iter()
+            .iter_mut()
             .map(|(u, e)| ((TYPE_MEMBERSHIP, u as &str), e));
 
         let a = self
             .aliases
-            .iter()
+            .iter_mut()
             .map(|(s, e)| ((TYPE_ALIASES, s as &str), e));
 
         let i = self
             .invites
-            .iter()
+            .iter_mut()
             .map(|(t, e)| ((TYPE_THIRD_PARTY_INVITE, t as &str), e));
 
         let o = self
             .others
-            .iter()
-            .flat_map(|(t, h)| h.iter().map(move |(s, e)| ((t as &str, s as &str), e)));
+            .iter_mut()
+            .flat_map(|(t, h)| h.iter_mut().map(move |(s, e)| ((t as &str, s as &str), e)));
 
         w.chain(m).chain(a).chain(i).chain(o)
     }
@@ -284,6 +560,30 @@ where
         w.chain(m).chain(a).chain(i).chain(o)
     }
 
+    /// Returns an iterator over mutable references to all values in the
+    /// state map
+    pub fn values_mut(&mut self) -> impl Iterator<Item = &mut E> {
+        let w = self.well_known.values_mut();
+
+        let m = self.membership.values_mut();
+
+        let a = self.aliases.values_mut();
+
+        let i = self.invites.values_mut();
+
+        let o = self.others.values_mut().flat_map(|h| h.values_mut());
+
+        w.chain(m).chain(a).chain(i).chain(o)
+    }
+
+    /// Clears the state map, returning all keys and values as an iterator.
+    /// Keeps the allocated memory for reuse.
+    ///
+    /// Any types marked as partial are marked as full again.
+    pub fn drain(&mut self) -> Drain<'_, E, S> {
+        Drain::new(self)
+    }
+
     /// Returns an iterator over all entries with a type of `m.room.member`,
     /// returning the state_key and value
     pub fn iter_members(&self) -> impl Iterator<Item = (&str, &E)> {
@@ -298,7 +598,7 @@ where
     pub fn iter_join_rules(&self) -> impl Iterator<Item = (&str, &E)> {
         let i = self
             .well_known
-            .get(&WellKnownEmptyKeys::JoinRules)
+            .get(WellKnownEmptyKeys::JoinRules)
             .into_iter()
             .map(|e| ("", e));
 
@@ -348,40 +648,43 @@ where
     }
 }
 
-impl<E> StateMap<E>
+impl<E, S> StateMap<E, S>
 where
     E: Debug + Clone + Default,
+    S: BuildHasher + Clone,
 {
     /// Gets a mutable reference to a value in the map, inserting a default
     /// value if the entry doesn't exist.
     pub fn get_mut_or_default(&mut self, t: &str, s: &str) -> &mut E {
-        if s == "" {
+        if s.is_empty() {
             if let Some(key) = WellKnownEmptyKeys::from_str(t) {
-                return self.well_known.entry(key).or_insert_with(E::default);
+                return self.well_known.get_or_insert_with(key, E::default);
             }
         }
 
-        if let Some(entry) = match (t, s) {
-            (TYPE_MEMBERSHIP, user) => Some(self.membership.entry(user.into())),
-            (TYPE_ALIASES, server) => Some(self.aliases.entry(server.into())),
-            (TYPE_THIRD_PARTY_INVITE, token) => Some(self.invites.entry(token.into())),
+        match (t, s) {
+            (TYPE_MEMBERSHIP, user) => get_mut_or_default_in_bucket(&mut self.membership, user),
+            (TYPE_ALIASES, server) => get_mut_or_default_in_bucket(&mut self.aliases, server),
+            (TYPE_THIRD_PARTY_INVITE, token) => {
+                get_mut_or_default_in_bucket(&mut self.invites, token)
+            }
 
-            _ => None,
-        } {
-            entry.or_insert_with(E::default)
-        } else {
-            self.others
-                .entry(t.into())
-                .or_insert_with(HashMap::new)
-                .entry(s.into())
-                .or_insert_with(E::default)
+            (t, s) => {
+                if !self.others.contains_key(t) {
+                    self.others.insert(t.into(), SmallMap::new());
+                }
+                let hash_builder = self.others.hasher().clone();
+                let h = self.others.get_mut(t).expect("type was just inserted");
+                h.get_or_insert_with(s, E::default, &hash_builder)
+            }
         }
     }
 }
 
-impl<E> StateMap<E>
+impl<E, S> StateMap<E, S>
 where
     E: Debug + Clone + PartialEq,
+    S: BuildHasher + Clone,
 {
     /// Inserts the entry into the map if it doesn't conflict with an existing
     /// entry (either the key doesn't exist or the values are the same)
@@ -392,115 +695,158 @@ where
         F: Borrow<E>,
     {
         let value = v.borrow();
-        if s == "" {
+        if s.is_empty() {
             if let Some(key) = WellKnownEmptyKeys::from_str(t) {
-                match self.well_known.entry(key) {
-                    hash_map::Entry::Occupied(o) => {
-                        if o.get() != value {
-                            return Some(o.remove());
-                        } else {
-                            return None;
-                        }
-                    }
-                    hash_map::Entry::Vacant(v) => {
-                        v.insert(value.clone());
-                        return None;
+                return match self.well_known.get(key) {
+                    Some(e) if e != value => self.well_known.remove(key),
+                    Some(_) => None,
+                    None => {
+                        self.well_known.insert(key, value.clone());
+                        None
                     }
-                }
+                };
             }
         }
 
-        if let Some(entry) = match (t, s) {
-            (TYPE_MEMBERSHIP, user) => Some(self.membership.entry(user.into())),
-            (TYPE_ALIASES, server) => Some(self.aliases.entry(server.into())),
-            (TYPE_THIRD_PARTY_INVITE, token) => Some(self.invites.entry(token.into())),
-
-            _ => None,
-        } {
-            match entry {
-                hash_map::Entry::Occupied(o) => {
-                    if o.get() != value {
-                        return Some(o.remove());
-                    } else {
-                        return None;
-                    }
-                }
-                hash_map::Entry::Vacant(v) => {
-                    v.insert(value.clone());
-                    return None;
-                }
+        match (t, s) {
+            (TYPE_MEMBERSHIP, user) => add_or_remove_in_bucket(&mut self.membership, user, value),
+            (TYPE_ALIASES, server) => add_or_remove_in_bucket(&mut self.aliases, server, value),
+            (TYPE_THIRD_PARTY_INVITE, token) => {
+                add_or_remove_in_bucket(&mut self.invites, token, value)
             }
-        } else {
-            match self.others.entry(t.into()) {
-                hash_map::Entry::Occupied(mut o) => match o.get_mut().entry(s.into()) {
-                    hash_map::Entry::Occupied(o) => {
-                        if o.get() != value {
-                            return Some(o.remove());
-                        } else {
-                            return None;
-                        }
-                    }
-                    hash_map::Entry::Vacant(v) => {
-                        v.insert(value.clone());
-                        return None;
-                    }
-                },
-                hash_map::Entry::Vacant(v) => {
-                    v.insert(HashMap::new()).insert(s.into(), value.clone());
-                    return None;
+
+            (t, s) => {
+                let hash_builder = self.others.hasher().clone();
+                if let Some(h) = self.others.get_mut(t) {
+                    return h.add_or_remove(s, value, &hash_builder);
                 }
+
+                self.others
+                    .insert(t.into(), SmallMap::with_entry(s.into(), value.clone()));
+                None
             }
         }
     }
 }
 
-impl<E> FromIterator<((String, String), E)> for StateMap<E>
+/// Inserts the entry into the bucket, only allocating a new key if there
+/// isn't already an entry for it.
+fn insert_in_bucket<E, S>(map: &mut HashMap<String, E, S>, key: &str, value: E) -> Option<E>
+where
+    S: BuildHasher,
+{
+    if let Some(e) = map.get_mut(key) {
+        return Some(mem::replace(e, value));
+    }
+
+    map.insert(key.into(), value);
+    None
+}
+
+/// Gets the entry in the bucket, inserting a default value (and allocating a
+/// new key) if it doesn't exist.
+fn get_mut_or_default_in_bucket<'a, E, S>(
+    map: &'a mut HashMap<String, E, S>,
+    key: &str,
+) -> &'a mut E
+where
+    E: Default,
+    S: BuildHasher,
+{
+    if !map.contains_key(key) {
+        map.insert(key.into(), E::default());
+    }
+    map.get_mut(key).expect("key was just inserted")
+}
+
+/// Implements [`StateMap::add_or_remove`] for a single bucket, only
+/// allocating a new key if there isn't already an entry for it.
+fn add_or_remove_in_bucket<E, S>(map: &mut HashMap<String, E, S>, key: &str, value: &E) -> Option<E>
+where
+    E: Clone + PartialEq,
+    S: BuildHasher,
+{
+    match map.get(key) {
+        Some(e) if e != value => map.remove(key),
+        Some(_) => None,
+        None => {
+            map.insert(key.into(), value.clone());
+            None
+        }
+    }
+}
+
+/// Maps the values of a bucket to a new type, reusing the keys and hasher.
+fn try_map_bucket<K, E, F, Err, S, M>(
+    map: HashMap<K, E, S>,
+    mut f: M,
+) -> Result<HashMap<K, F, S>, Err>
+where
+    K: Eq + Hash,
+    S: BuildHasher + Clone,
+    M: FnMut(&K, E) -> Result<F, Err>,
+{
+    let mut mapped = HashMap::with_capacity_and_hasher(map.len(), map.hasher().clone());
+
+    for (k, e) in map {
+        let value = f(&k, e)?;
+        mapped.insert(k, value);
+    }
+
+    Ok(mapped)
+}
+
+impl<E, S> FromIterator<((String, String), E)> for StateMap<E, S>
 where
     E: Debug + Clone,
+    S: BuildHasher + Clone + Default,
 {
-    fn from_iter<T: IntoIterator<Item = (((String, String), E))>>(iter: T) -> StateMap<E> {
-        let mut state_map = StateMap::new();
+    fn from_iter<T: IntoIterator<Item = ((String, String), E)>>(iter: T) -> StateMap<E, S> {
+        let mut state_map = StateMap::with_hasher(S::default());
 
         for ((t, s), e) in iter {
-            state_map.insert(&t, &s, e);
+            state_map.insert_owned(t, s, e);
         }
 
         state_map
     }
 }
 
-impl<'a, E> FromIterator<((&'a str, &'a str), E)> for StateMap<E>
+impl<'a, E, S> FromIterator<((&'a str, &'a str), E)> for StateMap<E, S>
 where
     E: Debug + Clone,
+    S: BuildHasher + Clone + Default,
 {
-    fn from_iter<T: IntoIterator<Item = (((&'a str, &'a str), E))>>(iter: T) -> StateMap<E> {
-        let mut state_map = StateMap::new();
+    fn from_iter<T: IntoIterator<Item = ((&'a str, &'a str), E)>>(iter: T) -> StateMap<E, S> {
+        let mut state_map = StateMap::with_hasher(S::default());
 
         for ((t, s), e) in iter {
-            state_map.insert(&t, &s, e);
+            state_map.insert(t, s, e);
         }
 
         state_map
     }
 }
 
-impl<E> Extend<((String, String), E)> for StateMap<E>
+impl<E, S> Extend<((String, String), E)> for StateMap<E, S>
 where
     E: Debug + Clone,
+    S: BuildHasher + Clone,
 {
     fn extend<T>(&mut self, iter: T)
     where
         T: IntoIterator<Item = ((String, String), E)>,
     {
         for ((t, s), e) in iter {
-            self.insert(&t, &s, e);
+            self.insert_owned(t, s, e);
         }
     }
 }
 
-impl<'a, E> Extend<((&'a str, &'a str), E)> for StateMap<E>
+impl<'a, E, S> Extend<((&'a str, &'a str), E)> for StateMap<E, S>
 where
     E: Debug + Clone,
+    S: BuildHasher + Clone,
 {
     fn extend<T>(&mut self, iter: T)
     where
@@ -512,6 +858,30 @@ where
     }
 }
 
+/// Maps are only equal if they also have the same types marked as partial, as
+/// a map holding partial state isn't the same as one holding the full state.
+impl<E, S> PartialEq for StateMap<E, S>
+where
+    E: Debug + Clone + PartialEq,
+    S: BuildHasher,
+{
+    fn eq(&self, other: &StateMap<E, S>) -> bool {
+        self.well_known == other.well_known
+            && self.membership == other.membership
+            && self.aliases == other.aliases
+            && self.invites == other.invites
+            && self.others == other.others
+            && self.partial_types == other.partial_types
+    }
+}
+
+impl<E, S> Eq for StateMap<E, S>
+where
+    E: Debug + Clone + Eq,
+    S: BuildHasher,
+{
+}
+
 #[test]
 fn add_or_remove_test() {
     let mut state_map = StateMap::new();
@@ -561,3 +931,171 @@ fn iter_test() {
 
     assert_eq!(expected, actual_entries);
 }
+
+#[test]
+fn with_hasher_test() {
+    use std::collections::hash_map::DefaultHasher;
+    use std::hash::BuildHasherDefault;
+
+    let mut state_map = StateMap::with_hasher(BuildHasherDefault::<DefaultHasher>::default());
+
+    state_map.insert(TYPE_POWER_LEVELS, "", 1);
+    state_map.insert(TYPE_MEMBERSHIP, "@alice:example.com", 2);
+    state_map.insert("test", "test2", 3);
+
+    assert_eq!(state_map.get(TYPE_POWER_LEVELS, ""), Some(&1));
+    assert_eq!(
+        state_map.get(TYPE_MEMBERSHIP, "@alice:example.com"),
+        Some(&2)
+    );
+    assert_eq!(state_map.get("test", "test2"), Some(&3));
+    assert_eq!(state_map.len(), 3);
+
+    let collected: StateMap<_, BuildHasherDefault<DefaultHasher>> =
+        state_map.iter().map(|(k, v)| (k, *v)).collect();
+    assert_eq!(state_map, collected);
+}
+
+#[test]
+fn iter_mut_test() {
+    let mut state_map = StateMap::new();
+
+    state_map.insert(TYPE_POWER_LEVELS, "", 1);
+    state_map.insert(TYPE_MEMBERSHIP, "@alice:example.com", 2);
+    state_map.insert("test", "test2", 3);
+
+    for ((t, _), e) in state_map.iter_mut() {
+        if t == TYPE_MEMBERSHIP {
+            *e += 10;
+        }
+    }
+
+    for e in state_map.values_mut() {
+        *e += 100;
+    }
+
+    assert_eq!(state_map.get(TYPE_POWER_LEVELS, ""), Some(&101));
+    assert_eq!(
+        state_map.get(TYPE_MEMBERSHIP, "@alice:example.com"),
+        Some(&112)
+    );
+    assert_eq!(state_map.get("test", "test2"), Some(&103));
+}
+
+#[test]
+fn retain_test() {
+    let mut state_map = StateMap::new();
+
+    state_map.insert(TYPE_POWER_LEVELS, "", 1);
+    state_map.insert(TYPE_POWER_LEVELS, "foo", 2);
+    state_map.insert(TYPE_MEMBERSHIP, "@alice:example.com", 3);
+    state_map.insert(TYPE_MEMBERSHIP, "@bob:example.com", 4);
+    state_map.insert("test", "a", 5);
+    state_map.insert("test", "b", 6);
+
+    state_map.retain_type(TYPE_MEMBERSHIP, |_, e| *e != 3);
+    assert_eq!(state_map.get(TYPE_MEMBERSHIP, "@alice:example.com"), None);
+    assert_eq!(state_map.get(TYPE_MEMBERSHIP, "@bob:example.com"), Some(&4));
+
+    state_map.retain_type(TYPE_POWER_LEVELS, |s, _| s.is_empty());
+    assert_eq!(state_map.get(TYPE_POWER_LEVELS, ""), Some(&1));
+    assert_eq!(state_map.get(TYPE_POWER_LEVELS, "foo"), None);
+    assert!(!state_map.others.contains_key(TYPE_POWER_LEVELS));
+
+    state_map.retain(|t, _, e| {
+        *e *= 10;
+        t != "test"
+    });
+    assert!(state_map.others.is_empty());
+    assert_eq!(state_map.len(), 2);
+    assert_eq!(state_map.get(TYPE_POWER_LEVELS, ""), Some(&10));
+    assert_eq!(
+        state_map.get(TYPE_MEMBERSHIP, "@bob:example.com"),
+        Some(&40)
+    );
+}
+
+#[test]
+fn map_values_test() {
+    let mut state_map = StateMap::new();
+
+    state_map.insert(TYPE_POWER_LEVELS, "", 1);
+    state_map.insert(TYPE_MEMBERSHIP, "@alice:example.com", 2);
+    state_map.insert("test", "test2", 3);
+
+    let mapped = state_map
+        .clone()
+        .map_values(|t, s, e| format!("{}/{}/{}", t, s, e));
+    assert_eq!(mapped.len(), 3);
+    assert_eq!(
+        mapped.get(TYPE_MEMBERSHIP, "@alice:example.com"),
+        Some(&format!("{}/@alice:example.com/2", TYPE_MEMBERSHIP))
+    );
+    assert_eq!(
+        mapped.get("test", "test2"),
+        Some(&"test/test2/3".to_string())
+    );
+
+    let res = state_map
+        .clone()
+        .try_map_values(|_, _, e| if e < 3 { Ok(e * 2) } else { Err(e) });
+    assert_eq!(res, Err(3));
+
+    let res = state_map
+        .try_map_values(|_, _, e| Ok::<_, ()>(e * 2))
+        .unwrap();
+    assert_eq!(res.get(TYPE_POWER_LEVELS, ""), Some(&2));
+}
+
+#[test]
+fn remove_test() {
+    let mut state_map = StateMap::new();
+
+    state_map.insert(TYPE_POWER_LEVELS, "", 1);
+    state_map.insert(TYPE_MEMBERSHIP, "@alice:example.com", 2);
+    state_map.insert("test", "test2", 3);
+
+    assert_eq!(state_map.remove(TYPE_POWER_LEVELS, ""), Some(1));
+    assert_eq!(state_map.remove(TYPE_POWER_LEVELS, ""), None);
+    assert_eq!(
+        state_map.remove(TYPE_MEMBERSHIP, "@alice:example.com"),
+        Some(2)
+    );
+    assert_eq!(state_map.remove("test", "test2"), Some(3));
+    assert_eq!(state_map.remove("test", "missing"), None);
+
+    assert!(state_map.is_empty());
+    assert!(state_map.others.is_empty());
+}
+
+#[test]
+fn insert_owned_test() {
+    let mut state_map = StateMap::new();
+
+    for &(t, s) in &[
+        ("test", "test2"),
+        (TYPE_POWER_LEVELS, ""),
+        (TYPE_POWER_LEVELS, "foo"),
+        (TYPE_MEMBERSHIP, "foo"),
+    ] {
+        assert_eq!(
+            state_map.insert_owned(t.to_string(), s.to_string(), 1),
+            None
+        );
+        assert_eq!(state_map.insert(t, s, 2), Some(1));
+        assert_eq!(
+            state_map.insert_owned(t.to_string(), s.to_string(), 3),
+            Some(2)
+        );
+        assert_eq!(state_map.get(t, s), Some(&3));
+
+        *state_map.get_mut_or_default(t, s) += 1;
+        assert_eq!(state_map.get(t, s), Some(&4));
+    }
+
+    assert_eq!(state_map.len(), 4);
+    assert_eq!(
+        state_map.get_well_known(WellKnownEmptyKeys::PowerLevels),
+        Some(&4)
+    );
+}
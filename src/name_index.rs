@@ -0,0 +1,202 @@
+// Copyright 2018 New Vector Ltd
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not
+// use this file except in compliance with the License. You may obtain a copy of
+// the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS, WITHOUT
+// WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied. See the
+// License for the specific language governing permissions and limitations under
+// the License.
+
+//! An index of member display names, so that they can be disambiguated
+//! without scanning the whole membership of the room.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt::Debug;
+use std::hash::BuildHasher;
+
+use {Membership, RoomNameEvent, StateChange, StateMap, TYPE_MEMBERSHIP};
+
+/// An index from display name to the joined and invited members using it,
+/// derived from the membership bucket of a `StateMap`.
+///
+/// The index must be kept up to date by calling [`DisplayNameIndex::update`]
+/// (or [`DisplayNameIndex::apply`]) whenever a member entry changes.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DisplayNameIndex {
+    /// The display name of every member that has one.
+    names: HashMap<String, String>,
+    /// The joined and invited members using each display name.
+    users: HashMap<String, HashSet<String>>,
+}
+
+impl DisplayNameIndex {
+    /// Builds an index of the members in the state map.
+    pub fn new<E, S>(state_map: &StateMap<E, S>) -> DisplayNameIndex
+    where
+        E: Debug + Clone + RoomNameEvent,
+        S: BuildHasher + Clone,
+    {
+        let mut index = DisplayNameIndex::default();
+        for (user, e) in state_map.iter_members() {
+            index.update(user, Some(e));
+        }
+        index
+    }
+
+    /// Updates the index with the new member event for `user`, or `None` if
+    /// the entry has been removed.
+    pub fn update<E: RoomNameEvent>(&mut self, user: &str, e: Option<&E>) {
+        if let Some(old) = self.names.remove(user) {
+            if let Some(users) = self.users.get_mut(&old) {
+                users.remove(user);
+                if users.is_empty() {
+                    self.users.remove(&old);
+                }
+            }
+        }
+
+        let e = match e {
+            Some(e) => e,
+            None => return,
+        };
+
+        let displayname = match e.displayname().filter(|d| !d.is_empty()) {
+            Some(displayname) => displayname,
+            None => return,
+        };
+
+        self.names.insert(user.to_string(), displayname.to_string());
+
+        match e.membership() {
+            Membership::Join | Membership::Invite => {
+                self.users
+                    .entry(displayname.to_string())
+                    .or_default()
+                    .insert(user.to_string());
+            }
+            Membership::Knock | Membership::Leave | Membership::Ban => {}
+        }
+    }
+
+    /// Updates the index with a change made to a [`JournaledStateMap`],
+    /// ignoring changes to anything other than member entries.
+    ///
+    /// [`JournaledStateMap`]: ::JournaledStateMap
+    pub fn apply<E: RoomNameEvent>(&mut self, change: &StateChange<E>) {
+        let (t, user) = change.key();
+        if t != TYPE_MEMBERSHIP {
+            return;
+        }
+
+        match *change {
+            StateChange::Inserted { ref value, .. } => self.update(user, Some(value)),
+            StateChange::Replaced { ref new, .. } => self.update(user, Some(new)),
+            StateChange::Removed { .. } => self.update::<E>(user, None),
+        }
+    }
+
+    /// The joined and invited members using the given display name.
+    pub fn users_with_name(&self, displayname: &str) -> impl Iterator<Item = &str> {
+        self.users
+            .get(displayname)
+            .into_iter()
+            .flat_map(|users| users.iter().map(|u| u as &str))
+    }
+
+    /// Returns the display name of the given member, as
+    /// [`member_display_name`](::member_display_name).
+    pub fn disambiguated_name(&self, user: &str) -> String {
+        let displayname = match self.names.get(user) {
+            Some(displayname) => displayname,
+            None => return user.to_string(),
+        };
+
+        let ambiguous = self.users_with_name(displayname).any(|other| other != user);
+
+        if ambiguous {
+            format!("{} ({})", displayname, user)
+        } else {
+            displayname.clone()
+        }
+    }
+}
+
+#[test]
+fn display_name_index_test() {
+    use MembershipEvent;
+
+    #[derive(Debug, Clone)]
+    struct Member(Membership, &'static str);
+
+    impl MembershipEvent for Member {
+        fn membership(&self) -> Membership {
+            self.0
+        }
+
+        fn stream_ordering(&self) -> i64 {
+            0
+        }
+    }
+
+    impl RoomNameEvent for Member {
+        fn displayname(&self) -> Option<&str> {
+            Some(self.1)
+        }
+    }
+
+    let mut state_map = StateMap::new();
+    state_map.insert(
+        TYPE_MEMBERSHIP,
+        "@alice:example.org",
+        Member(Membership::Join, "Alice"),
+    );
+    state_map.insert(
+        TYPE_MEMBERSHIP,
+        "@bob:example.org",
+        Member(Membership::Join, ""),
+    );
+    state_map.insert(
+        TYPE_MEMBERSHIP,
+        "@eve:example.org",
+        Member(Membership::Leave, "Alice"),
+    );
+
+    let mut state_map = state_map.into_journaled();
+    let mut index = DisplayNameIndex::new(&state_map);
+    assert_eq!(index.disambiguated_name("@alice:example.org"), "Alice");
+    assert_eq!(
+        index.disambiguated_name("@bob:example.org"),
+        "@bob:example.org"
+    );
+    assert_eq!(
+        index.disambiguated_name("@eve:example.org"),
+        "Alice (@eve:example.org)"
+    );
+
+    state_map.insert(
+        TYPE_MEMBERSHIP,
+        "@eve:example.org",
+        Member(Membership::Invite, "Alice"),
+    );
+    state_map.insert("test", "a", Member(Membership::Join, "Alice"));
+    for change in state_map.drain_journal() {
+        index.apply(&change);
+    }
+    assert_eq!(
+        index.disambiguated_name("@alice:example.org"),
+        "Alice (@alice:example.org)"
+    );
+    assert_eq!(index.users_with_name("Alice").count(), 2);
+
+    state_map.remove(TYPE_MEMBERSHIP, "@eve:example.org");
+    for change in state_map.drain_journal() {
+        index.apply(&change);
+    }
+    assert_eq!(index.disambiguated_name("@alice:example.org"), "Alice");
+    assert_eq!(index, DisplayNameIndex::new(&state_map));
+}
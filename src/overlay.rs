@@ -0,0 +1,197 @@
+// Copyright 2018 New Vector Ltd
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not
+// use this file except in compliance with the License. You may obtain a copy of
+// the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS, WITHOUT
+// WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied. See the
+// License for the specific language governing permissions and limitations under
+// the License.
+
+//! A copy on write view over a borrowed `StateMap`.
+
+use std::collections::hash_map::RandomState;
+use std::fmt::Debug;
+use std::hash::BuildHasher;
+
+use StateMap;
+
+/// A set of changes to a `StateMap`, where a value of `None` means the entry
+/// has been removed.
+pub type StateDelta<E, S = RandomState> = StateMap<Option<E>, S>;
+
+/// A lightweight view of a base state map plus some local changes, e.g. to
+/// compute the state after an event without cloning the base map.
+///
+/// Only the inserted entries and removed keys ("tombstones") are stored, and
+/// all lookups check those before falling back to the base map.
+#[derive(Debug, Clone)]
+pub struct StateMapOverlay<'a, E: Debug + Clone + 'a, S: 'a = RandomState> {
+    base: &'a StateMap<E, S>,
+    delta: StateDelta<E, S>,
+}
+
+impl<E, S> StateMap<E, S>
+where
+    E: Debug + Clone,
+    S: BuildHasher + Clone,
+{
+    /// Creates an overlay on top of this map.
+    pub fn overlay(&self) -> StateMapOverlay<'_, E, S> {
+        StateMapOverlay::new(self)
+    }
+
+    /// Applies the changes in `delta` to the map, inserting entries that have
+    /// a value and removing those that don't.
+    pub fn apply_delta(&mut self, delta: StateDelta<E, S>) {
+        for ((t, s), e) in delta {
+            match e {
+                Some(e) => self.insert_owned(t, s, e),
+                None => self.remove(&t, &s),
+            };
+        }
+    }
+}
+
+impl<'a, E, S> StateMapOverlay<'a, E, S>
+where
+    E: Debug + Clone,
+    S: BuildHasher + Clone,
+{
+    /// Creates an overlay with no changes on top of `base`.
+    pub fn new(base: &'a StateMap<E, S>) -> StateMapOverlay<'a, E, S> {
+        StateMapOverlay {
+            base,
+            delta: StateMap::with_hasher(base.hasher().clone()),
+        }
+    }
+
+    /// The map the overlay is on top of.
+    pub fn base(&self) -> &'a StateMap<E, S> {
+        self.base
+    }
+
+    pub fn get(&self, t: &str, s: &str) -> Option<&E> {
+        match self.delta.get(t, s) {
+            Some(e) => e.as_ref(),
+            None => self.base.get(t, s),
+        }
+    }
+
+    pub fn contains_key(&self, t: &str, s: &str) -> bool {
+        self.get(t, s).is_some()
+    }
+
+    /// Inserts an entry into the overlay, leaving the base map untouched.
+    pub fn insert(&mut self, t: &str, s: &str, value: E) {
+        self.delta.insert(t, s, Some(value));
+    }
+
+    /// Removes an entry from the overlay, returning whether it was present.
+    /// The base map is left untouched.
+    pub fn remove(&mut self, t: &str, s: &str) -> bool {
+        let present = self.contains_key(t, s);
+
+        if self.base.contains_key(t, s) {
+            self.delta.insert(t, s, None);
+        } else {
+            self.delta.remove(t, s);
+        }
+
+        present
+    }
+
+    /// Returns an iterator over all keys and values visible through the
+    /// overlay.
+    pub fn iter(&self) -> impl Iterator<Item = ((&str, &str), &E)> {
+        let delta = &self.delta;
+
+        let base = self
+            .base
+            .iter()
+            .filter(move |&((t, s), _)| !delta.contains_key(t, s));
+
+        let local = self
+            .delta
+            .iter()
+            .filter_map(|(k, e)| e.as_ref().map(|e| (k, e)));
+
+        base.chain(local)
+    }
+
+    /// The number of entries visible through the overlay.
+    pub fn len(&self) -> usize {
+        let mut len = self.base.len();
+
+        for ((t, s), e) in self.delta.iter() {
+            match (self.base.contains_key(t, s), e.is_some()) {
+                (false, true) => len += 1,
+                (true, false) => len -= 1,
+                _ => {}
+            }
+        }
+
+        len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the changes made on top of the base map, which can be applied
+    /// to it with [`StateMap::apply_delta`].
+    pub fn into_delta(self) -> StateDelta<E, S> {
+        self.delta
+    }
+
+    /// Creates a new state map with the changes applied to a copy of the base
+    /// map.
+    pub fn materialize(self) -> StateMap<E, S> {
+        let mut state_map = self.base.clone();
+        state_map.apply_delta(self.delta);
+        state_map
+    }
+}
+
+#[test]
+fn overlay_test() {
+    use std::collections::HashMap;
+    use {TYPE_MEMBERSHIP, TYPE_POWER_LEVELS};
+
+    let mut base = StateMap::new();
+    base.insert(TYPE_POWER_LEVELS, "", 1);
+    base.insert(TYPE_MEMBERSHIP, "@alice:example.com", 2);
+    base.insert("test", "a", 3);
+
+    let mut overlay = base.overlay();
+    overlay.insert(TYPE_POWER_LEVELS, "", 10);
+    overlay.insert(TYPE_MEMBERSHIP, "@bob:example.com", 20);
+    assert!(overlay.remove("test", "a"));
+    assert!(!overlay.remove("test", "missing"));
+    overlay.insert("test", "b", 4);
+    assert!(overlay.remove("test", "b"));
+
+    assert_eq!(overlay.get(TYPE_POWER_LEVELS, ""), Some(&10));
+    assert_eq!(overlay.get(TYPE_MEMBERSHIP, "@alice:example.com"), Some(&2));
+    assert!(!overlay.contains_key("test", "a"));
+    assert!(!overlay.contains_key("test", "b"));
+    assert_eq!(overlay.len(), 3);
+
+    let visible: HashMap<_, _> = overlay.iter().map(|(k, e)| (k, *e)).collect();
+    assert_eq!(visible.len(), 3);
+    assert_eq!(visible[&(TYPE_POWER_LEVELS, "")], 10);
+
+    // The base map is untouched.
+    assert_eq!(base.get("test", "a"), Some(&3));
+
+    let materialized = overlay.clone().materialize();
+    assert_eq!(materialized.len(), 3);
+
+    let mut applied = base.clone();
+    applied.apply_delta(overlay.into_delta());
+    assert_eq!(applied, materialized);
+}
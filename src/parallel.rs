@@ -0,0 +1,162 @@
+// Copyright 2018 New Vector Ltd
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not
+// use this file except in compliance with the License. You may obtain a copy of
+// the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS, WITHOUT
+// WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied. See the
+// License for the specific language governing permissions and limitations under
+// the License.
+
+//! Parallel iteration and collection of `StateMap`s using rayon, enabled with
+//! the `rayon` feature.
+
+use std::fmt::Debug;
+use std::hash::BuildHasher;
+
+use rayon::iter::{
+    FromParallelIterator, IntoParallelIterator, IntoParallelRefIterator, ParallelIterator,
+};
+
+use {StateMap, WellKnownEmptyKeys, TYPE_ALIASES, TYPE_MEMBERSHIP, TYPE_THIRD_PARTY_INVITE};
+
+impl<E, S> StateMap<E, S>
+where
+    E: Debug + Clone + Sync,
+    S: BuildHasher + Clone + Sync,
+{
+    /// Returns a parallel iterator over all keys and values in the state map.
+    ///
+    /// Each of the internal buckets (and each of the per type maps for
+    /// uncommon types) are split up and processed in parallel.
+    pub fn par_iter(&self) -> impl ParallelIterator<Item = ((&str, &str), &E)> {
+        let w = WellKnownEmptyKeys::ALL
+            .par_iter()
+            .filter_map(move |&k| self.well_known.get(k).map(|e| ((k.as_str(), ""), e)));
+
+        let m = self
+            .par_iter_members()
+            .map(|(u, e)| ((TYPE_MEMBERSHIP, u), e));
+
+        let a = self
+            .aliases
+            .par_iter()
+            .map(|(s, e)| ((TYPE_ALIASES, s as &str), e));
+
+        let i = self
+            .invites
+            .par_iter()
+            .map(|(t, e)| ((TYPE_THIRD_PARTY_INVITE, t as &str), e));
+
+        let o = self
+            .others
+            .par_iter()
+            .flat_map(|(t, h)| h.par_iter().map(move |(s, e)| ((t as &str, s as &str), e)));
+
+        w.chain(m).chain(a).chain(i).chain(o)
+    }
+
+    /// Returns a parallel iterator over all entries with a type of
+    /// `m.room.member`, returning the state_key and value
+    pub fn par_iter_members(&self) -> impl ParallelIterator<Item = (&str, &E)> {
+        self.membership.par_iter().map(|(u, e)| (u as &str, e))
+    }
+}
+
+impl<E, S> FromParallelIterator<((String, String), E)> for StateMap<E, S>
+where
+    E: Debug + Clone + Send,
+    S: BuildHasher + Clone + Default + Send,
+{
+    /// Builds a state map in parallel, by building a state map per rayon job
+    /// and then merging them together.
+    ///
+    /// As with `collect`, if a key appears more than once the last value wins.
+    fn from_par_iter<I>(par_iter: I) -> StateMap<E, S>
+    where
+        I: IntoParallelIterator<Item = ((String, String), E)>,
+    {
+        par_iter
+            .into_par_iter()
+            .fold(
+                || StateMap::with_hasher(S::default()),
+                |mut state_map, ((t, s), e)| {
+                    state_map.insert_owned(t, s, e);
+                    state_map
+                },
+            )
+            .reduce(
+                || StateMap::with_hasher(S::default()),
+                |mut a, b| {
+                    // `b` holds later entries than `a`, so its values win.
+                    a.merge_with(b, |_, _, theirs| Some(theirs));
+                    a
+                },
+            )
+    }
+}
+
+impl<'a, E, S> FromParallelIterator<((&'a str, &'a str), E)> for StateMap<E, S>
+where
+    E: Debug + Clone + Send,
+    S: BuildHasher + Clone + Default + Send,
+{
+    fn from_par_iter<I>(par_iter: I) -> StateMap<E, S>
+    where
+        I: IntoParallelIterator<Item = ((&'a str, &'a str), E)>,
+    {
+        par_iter
+            .into_par_iter()
+            .map(|((t, s), e)| ((t.to_string(), s.to_string()), e))
+            .collect()
+    }
+}
+
+#[test]
+fn par_iter_test() {
+    use std::collections::HashMap;
+    use TYPE_POWER_LEVELS;
+
+    let state_map: StateMap<usize> = (0..1000)
+        .into_par_iter()
+        .map(|i| {
+            (
+                (
+                    TYPE_MEMBERSHIP.to_string(),
+                    format!("@user{}:example.com", i),
+                ),
+                i,
+            )
+        })
+        .chain(rayon::iter::once((
+            (TYPE_POWER_LEVELS.to_string(), String::new()),
+            1000,
+        )))
+        .chain(rayon::iter::once((
+            ("test".to_string(), "a".to_string()),
+            1001,
+        )))
+        .collect();
+
+    assert_eq!(state_map.len(), 1002);
+    assert_eq!(
+        state_map.get(TYPE_MEMBERSHIP, "@user10:example.com"),
+        Some(&10)
+    );
+    assert_eq!(state_map.par_iter_members().count(), 1000);
+
+    let expected: HashMap<_, _> = state_map.iter().collect();
+    let actual: HashMap<_, _> = state_map.par_iter().collect();
+    assert_eq!(expected, actual);
+
+    // Duplicate keys are last-wins, as with `collect`.
+    let state_map: StateMap<usize> = (0..10000)
+        .into_par_iter()
+        .map(|i| (("test", "a"), i))
+        .collect();
+    assert_eq!(state_map.get("test", "a"), Some(&9999));
+}
@@ -0,0 +1,177 @@
+// Copyright 2018 New Vector Ltd
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not
+// use this file except in compliance with the License. You may obtain a copy of
+// the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS, WITHOUT
+// WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied. See the
+// License for the specific language governing permissions and limitations under
+// the License.
+
+//! Tracking of types for which a `StateMap` only holds partial state, e.g.
+//! the membership of a room after a partial state join (MSC3706).
+
+use std::fmt::Debug;
+use std::hash::BuildHasher;
+use std::mem;
+
+use StateMap;
+
+/// The result of looking up a key in a state map that may hold partial state.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StateLookup<T> {
+    /// The key is in the map.
+    Present(T),
+    /// The key is known to not be in the state.
+    Absent,
+    /// The key isn't in the map, but as the type is only partially known it
+    /// may be in the full state.
+    Unknown,
+}
+
+impl<T> StateLookup<T> {
+    /// Converts into an `Option`, treating unknown entries as absent.
+    pub fn present(self) -> Option<T> {
+        match self {
+            StateLookup::Present(e) => Some(e),
+            StateLookup::Absent | StateLookup::Unknown => None,
+        }
+    }
+
+    pub fn is_unknown(&self) -> bool {
+        matches!(*self, StateLookup::Unknown)
+    }
+}
+
+impl<E, S> StateMap<E, S>
+where
+    E: Debug + Clone,
+    S: BuildHasher + Clone,
+{
+    /// Marks the type as only being partially known, so that lookups of
+    /// missing keys of that type return [`StateLookup::Unknown`].
+    pub fn mark_partial(&mut self, t: &str) {
+        if let Err(idx) = self.partial_types.binary_search_by(|p| p.as_str().cmp(t)) {
+            self.partial_types.insert(idx, t.to_string());
+        }
+    }
+
+    /// Marks the type as fully known again.
+    pub fn mark_full(&mut self, t: &str) {
+        if let Ok(idx) = self.partial_types.binary_search_by(|p| p.as_str().cmp(t)) {
+            self.partial_types.remove(idx);
+        }
+    }
+
+    /// Whether the type has been marked as only partially known.
+    pub fn is_partial(&self, t: &str) -> bool {
+        self.partial_types
+            .binary_search_by(|p| p.as_str().cmp(t))
+            .is_ok()
+    }
+
+    /// Whether the map holds the full state, i.e. no types are marked as
+    /// partial.
+    pub fn is_full_state(&self) -> bool {
+        self.partial_types.is_empty()
+    }
+
+    /// Returns an iterator over the types marked as partial.
+    pub fn partial_types(&self) -> impl Iterator<Item = &str> {
+        self.partial_types.iter().map(|t| t as &str)
+    }
+
+    /// Looks up the key, distinguishing between keys that are known to be
+    /// absent and those that might exist but haven't been fetched yet.
+    pub fn lookup(&self, t: &str, s: &str) -> StateLookup<&E> {
+        match self.get(t, s) {
+            Some(e) => StateLookup::Present(e),
+            None if self.is_partial(t) => StateLookup::Unknown,
+            None => StateLookup::Absent,
+        }
+    }
+
+    /// Upgrades the map to full state by adding the entries from `full`,
+    /// e.g. once the state of a room has been resynced after a partial join.
+    ///
+    /// Where a key is in both maps the value from `full` replaces ours for
+    /// types that were marked as partial, as ours may be stale, and ours is
+    /// kept for other types. All types are then marked as fully known.
+    pub fn merge_full_state(&mut self, full: StateMap<E, S>) {
+        let partial_types = mem::take(&mut self.partial_types);
+        let was_partial = |t: &str| {
+            partial_types
+                .binary_search_by(|p| p.as_str().cmp(t))
+                .is_ok()
+        };
+
+        self.merge_with(full, |(t, _), ours, theirs| {
+            if was_partial(t) {
+                Some(theirs)
+            } else {
+                Some(ours)
+            }
+        });
+        self.partial_types.clear();
+    }
+}
+
+#[test]
+fn partial_state_test() {
+    use {TYPE_MEMBERSHIP, TYPE_POWER_LEVELS};
+
+    let mut state_map = StateMap::new();
+    state_map.insert(TYPE_POWER_LEVELS, "", 1);
+    state_map.insert(TYPE_MEMBERSHIP, "@alice:example.com", 2);
+    state_map.mark_partial(TYPE_MEMBERSHIP);
+
+    assert!(!state_map.is_full_state());
+    assert_eq!(
+        state_map.lookup(TYPE_MEMBERSHIP, "@alice:example.com"),
+        StateLookup::Present(&2)
+    );
+    assert_eq!(
+        state_map.lookup(TYPE_MEMBERSHIP, "@bob:example.com"),
+        StateLookup::Unknown
+    );
+    assert_eq!(state_map.lookup("test", "a"), StateLookup::Absent);
+
+    let mut full = StateMap::new();
+    full.insert(TYPE_POWER_LEVELS, "", 10);
+    full.insert(TYPE_MEMBERSHIP, "@alice:example.com", 20);
+    full.insert(TYPE_MEMBERSHIP, "@bob:example.com", 3);
+
+    // The full state replaces our values of partial types, but not others.
+    state_map.merge_full_state(full);
+    assert!(state_map.is_full_state());
+    assert!(!state_map.is_partial(TYPE_MEMBERSHIP));
+    assert_eq!(
+        state_map.lookup(TYPE_POWER_LEVELS, ""),
+        StateLookup::Present(&1)
+    );
+    assert_eq!(
+        state_map.lookup(TYPE_MEMBERSHIP, "@alice:example.com"),
+        StateLookup::Present(&20)
+    );
+    assert_eq!(
+        state_map.lookup(TYPE_MEMBERSHIP, "@bob:example.com"),
+        StateLookup::Present(&3)
+    );
+    assert_eq!(
+        state_map.lookup(TYPE_MEMBERSHIP, "@carol:example.com"),
+        StateLookup::Absent
+    );
+
+    // Draining the map also forgets which types were partial.
+    state_map.mark_partial(TYPE_MEMBERSHIP);
+    state_map.drain();
+    assert!(state_map.is_full_state());
+    assert_eq!(
+        state_map.lookup(TYPE_MEMBERSHIP, "@alice:example.com"),
+        StateLookup::Absent
+    );
+}
@@ -0,0 +1,206 @@
+// Copyright 2018 New Vector Ltd
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not
+// use this file except in compliance with the License. You may obtain a copy of
+// the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS, WITHOUT
+// WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied. See the
+// License for the specific language governing permissions and limitations under
+// the License.
+
+//! Python bindings for `StateMap`, enabled with the `python` feature.
+//!
+//! This exposes a `state_map.StateMap` class which behaves like a read only
+//! `dict` keyed by `(type, state_key)` tuples, and is registered as a
+//! `collections.abc.Mapping`.
+
+use pyo3::exceptions::PyKeyError;
+use pyo3::prelude::*;
+use pyo3::pybacked::PyBackedStr;
+use pyo3::types::{PyDict, PyIterator, PyList, PyTuple};
+
+use StateMap;
+
+/// A `StateMap` holding arbitrary Python objects as values.
+#[pyclass(name = "StateMap", module = "state_map", mapping)]
+pub struct PyStateMap {
+    inner: StateMap<PyObject>,
+}
+
+/// Extracts a `(type, state_key)` tuple, returning `None` for any other key
+/// as it can't be in the map.
+fn extract_key(key: &Bound<'_, PyAny>) -> Option<(PyBackedStr, PyBackedStr)> {
+    key.extract().ok()
+}
+
+impl PyStateMap {
+    /// Gets a reference to the underlying state map.
+    pub fn as_state_map(&self) -> &StateMap<PyObject> {
+        &self.inner
+    }
+}
+
+impl From<StateMap<PyObject>> for PyStateMap {
+    fn from(inner: StateMap<PyObject>) -> PyStateMap {
+        PyStateMap { inner }
+    }
+}
+
+#[pymethods]
+impl PyStateMap {
+    /// Creates a new state map, optionally from a dict (or iterable of pairs)
+    /// mapping `(type, state_key)` tuples to values.
+    #[new]
+    #[pyo3(signature = (items = None))]
+    fn py_new(items: Option<&Bound<'_, PyAny>>) -> PyResult<PyStateMap> {
+        let items = match items {
+            Some(items) => items,
+            None => {
+                return Ok(PyStateMap {
+                    inner: StateMap::new(),
+                })
+            }
+        };
+
+        let mut entries: Vec<((String, String), PyObject)> = Vec::new();
+
+        if let Ok(dict) = items.downcast::<PyDict>() {
+            entries.reserve(dict.len());
+            for (k, v) in dict.iter() {
+                entries.push((k.extract()?, v.unbind()));
+            }
+        } else {
+            for item in items.iter()? {
+                entries.push(item?.extract()?);
+            }
+        }
+
+        Ok(PyStateMap {
+            inner: entries.into_iter().collect(),
+        })
+    }
+
+    fn __getitem__(&self, py: Python<'_>, key: &Bound<'_, PyAny>) -> PyResult<PyObject> {
+        let value = extract_key(key).and_then(|(t, s)| self.inner.get(&t, &s));
+        match value {
+            Some(value) => Ok(value.clone_ref(py)),
+            None => Err(PyKeyError::new_err((key.clone().unbind(),))),
+        }
+    }
+
+    fn __contains__(&self, key: &Bound<'_, PyAny>) -> bool {
+        match extract_key(key) {
+            Some((t, s)) => self.inner.contains_key(&t, &s),
+            None => false,
+        }
+    }
+
+    fn __len__(&self) -> usize {
+        self.inner.len()
+    }
+
+    fn __iter__<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyIterator>> {
+        self.keys(py).as_any().iter()
+    }
+
+    /// Returns the value for the key if it is in the map, else `default`.
+    #[pyo3(signature = (key, default = None))]
+    fn get(&self, py: Python<'_>, key: &Bound<'_, PyAny>, default: Option<PyObject>) -> PyObject {
+        let value = extract_key(key).and_then(|(t, s)| self.inner.get(&t, &s));
+        match value {
+            Some(value) => value.clone_ref(py),
+            None => default.unwrap_or_else(|| py.None()),
+        }
+    }
+
+    /// Returns a list of all `(type, state_key)` tuples in the map.
+    fn keys<'py>(&self, py: Python<'py>) -> Bound<'py, PyList> {
+        let keys: Vec<_> = self
+            .inner
+            .keys()
+            .map(|(t, s)| PyTuple::new_bound(py, [t, s]))
+            .collect();
+        PyList::new_bound(py, keys)
+    }
+
+    /// Returns a list of all values in the map.
+    fn values<'py>(&self, py: Python<'py>) -> Bound<'py, PyList> {
+        let values: Vec<_> = self.inner.values().map(|v| v.clone_ref(py)).collect();
+        PyList::new_bound(py, values)
+    }
+
+    /// Returns a list of all `((type, state_key), value)` pairs in the map.
+    fn items<'py>(&self, py: Python<'py>) -> Bound<'py, PyList> {
+        let items: Vec<PyObject> = self
+            .inner
+            .iter()
+            .map(|((t, s), v)| ((t, s), v.clone_ref(py)).into_py(py))
+            .collect();
+        PyList::new_bound(py, items)
+    }
+}
+
+/// The `state_map` Python module.
+#[pymodule]
+fn state_map(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    let py = m.py();
+
+    m.add_class::<PyStateMap>()?;
+
+    py.import_bound("collections.abc")?
+        .getattr("Mapping")?
+        .call_method1("register", (py.get_type_bound::<PyStateMap>(),))?;
+
+    Ok(())
+}
+
+#[test]
+fn python_mapping_test() {
+    use pyo3::types::IntoPyDict;
+
+    pyo3::prepare_freethreaded_python();
+
+    Python::with_gil(|py| {
+        let dict = vec![
+            (("m.room.create", ""), "$create"),
+            (("m.room.member", "@alice:example.com"), "$alice"),
+        ]
+        .into_py_dict_bound(py);
+
+        let state_map = Bound::new(py, PyStateMap::py_new(Some(dict.as_any())).unwrap()).unwrap();
+        let state_map = state_map.as_any();
+
+        assert_eq!(state_map.len().unwrap(), 2);
+
+        let value: String = state_map
+            .get_item(("m.room.member", "@alice:example.com"))
+            .unwrap()
+            .extract()
+            .unwrap();
+        assert_eq!(value, "$alice");
+
+        let err = state_map
+            .get_item(("m.room.member", "@bob:example.com"))
+            .unwrap_err();
+        assert!(err.is_instance_of::<PyKeyError>(py));
+
+        // Keys that aren't `(str, str)` tuples are missing rather than invalid.
+        let err = state_map.get_item("m.room.create").unwrap_err();
+        assert!(err.is_instance_of::<PyKeyError>(py));
+        let value = state_map
+            .call_method1("get", ("m.room.create", "default"))
+            .unwrap();
+        assert_eq!(value.extract::<String>().unwrap(), "default");
+        assert!(state_map.call_method1("get", ((1, 2),)).unwrap().is_none());
+        assert!(state_map.contains(("m.room.create", "")).unwrap());
+        assert!(!state_map.contains("m.room.create").unwrap());
+
+        let items: Vec<((String, String), String)> =
+            state_map.call_method0("items").unwrap().extract().unwrap();
+        assert_eq!(items.len(), 2);
+    });
+}
@@ -0,0 +1,265 @@
+// Copyright 2018 New Vector Ltd
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not
+// use this file except in compliance with the License. You may obtain a copy of
+// the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS, WITHOUT
+// WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied. See the
+// License for the specific language governing permissions and limitations under
+// the License.
+
+//! A `StateMap` wrapper that also indexes entries by value, e.g. to find the
+//! key of an event ID that is being redacted or purged.
+
+use std::borrow::Borrow;
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::hash::{BuildHasher, Hash};
+use std::ops::Deref;
+
+use heap_size::table_size;
+use {HeapSize, StateKey, StateKeyRef, StateMap};
+
+/// Wraps a `StateMap` and keeps a reverse index from each value to its key,
+/// created by [`StateMap::into_indexed`].
+///
+/// Values are expected to be unique, e.g. event IDs. If the same value is
+/// stored under more than one key only the most recently inserted key is
+/// indexed.
+///
+/// Reads go straight to the underlying map, as the wrapper derefs to it.
+#[derive(Debug, Clone)]
+pub struct IndexedStateMap<E: Debug + Clone + Hash + Eq, S = RandomState> {
+    state_map: StateMap<E, S>,
+    /// The keys are stored classified, so that e.g. the type of membership
+    /// entries isn't copied for every value.
+    keys: HashMap<E, StateKey, S>,
+}
+
+impl<E, S> StateMap<E, S>
+where
+    E: Debug + Clone + Hash + Eq,
+    S: BuildHasher + Clone,
+{
+    /// Wraps the map so that entries can be looked up by value.
+    pub fn into_indexed(self) -> IndexedStateMap<E, S> {
+        let mut keys = HashMap::with_capacity_and_hasher(self.len(), self.hasher().clone());
+        for (k, e) in self.iter_typed() {
+            keys.insert(e.clone(), k.into_owned());
+        }
+
+        IndexedStateMap {
+            state_map: self,
+            keys,
+        }
+    }
+}
+
+/// Whether `e` is indexed under the given key.
+fn is_indexed_under<E, S>(keys: &HashMap<E, StateKey, S>, e: &E, t: &str, s: &str) -> bool
+where
+    E: Hash + Eq,
+    S: BuildHasher,
+{
+    match keys.get(e) {
+        Some(k) => k.as_tuple() == (t, s),
+        None => false,
+    }
+}
+
+impl<E, S> IndexedStateMap<E, S>
+where
+    E: Debug + Clone + Hash + Eq,
+    S: BuildHasher + Clone,
+{
+    /// Removes `e` from the index, if it's indexed under the given key.
+    fn unindex(&mut self, t: &str, s: &str, e: &E) {
+        if is_indexed_under(&self.keys, e, t, s) {
+            self.keys.remove(e);
+        }
+    }
+
+    /// Returns the key of the entry with the given value.
+    pub fn key_for_value(&self, e: &E) -> Option<StateKeyRef<'_>> {
+        self.keys.get(e).map(StateKey::borrow_ref)
+    }
+
+    /// Removes the entry with the given value, returning its key.
+    pub fn remove_by_value(&mut self, e: &E) -> Option<StateKey> {
+        let key = self.keys.remove(e)?;
+        let (t, s) = key.as_tuple();
+        self.state_map.remove(t, s);
+        Some(key)
+    }
+
+    /// Unwraps the underlying map, discarding the index.
+    pub fn into_inner(self) -> StateMap<E, S> {
+        self.state_map
+    }
+
+    /// Inserts the entry into the map, as [`StateMap::insert`].
+    pub fn insert(&mut self, t: &str, s: &str, value: E) -> Option<E> {
+        let old = self.state_map.insert(t, s, value.clone());
+        if let Some(ref old) = old {
+            self.unindex(t, s, old);
+        }
+
+        self.keys.insert(value, StateKey::new(t, s));
+        old
+    }
+
+    /// Removes the entry from the map, as [`StateMap::remove`].
+    pub fn remove(&mut self, t: &str, s: &str) -> Option<E> {
+        let old = self.state_map.remove(t, s);
+        if let Some(ref old) = old {
+            self.unindex(t, s, old);
+        }
+        old
+    }
+
+    /// Retains only the entries for which the predicate returns true, as
+    /// [`StateMap::retain`]. Unlike that the predicate can't change the
+    /// values, as that would invalidate the index.
+    pub fn retain<F>(&mut self, mut f: F)
+    where
+        F: FnMut(&str, &str, &E) -> bool,
+    {
+        let keys = &mut self.keys;
+
+        self.state_map.retain(|t, s, e| {
+            let keep = f(t, s, e);
+            if !keep && is_indexed_under(keys, e, t, s) {
+                keys.remove(e);
+            }
+            keep
+        });
+    }
+
+    /// Inserts or removes the entry, as [`StateMap::add_or_remove`].
+    pub fn add_or_remove<F>(&mut self, t: &str, s: &str, v: F) -> Option<E>
+    where
+        F: Borrow<E>,
+    {
+        let value = v.borrow();
+        let existed = self.state_map.contains_key(t, s);
+        let removed = self.state_map.add_or_remove(t, s, value);
+
+        if let Some(ref removed) = removed {
+            self.unindex(t, s, removed);
+        } else if !existed {
+            self.keys.insert(value.clone(), StateKey::new(t, s));
+        }
+
+        removed
+    }
+}
+
+impl<E, S> IndexedStateMap<E, S>
+where
+    E: Debug + Clone + Hash + Eq + HeapSize,
+    S: BuildHasher + Clone,
+{
+    /// Estimates the number of bytes allocated on the heap by the reverse
+    /// index, on top of [`StateMap::heap_size`] for the map itself.
+    pub fn index_heap_size(&self) -> usize {
+        table_size(&self.keys)
+            + self
+                .keys
+                .iter()
+                .map(|(e, k)| e.heap_size() + k.heap_size())
+                .sum::<usize>()
+    }
+}
+
+impl<E, S> Deref for IndexedStateMap<E, S>
+where
+    E: Debug + Clone + Hash + Eq,
+{
+    type Target = StateMap<E, S>;
+
+    fn deref(&self) -> &StateMap<E, S> {
+        &self.state_map
+    }
+}
+
+#[test]
+fn reverse_index_test() {
+    use {TYPE_MEMBERSHIP, TYPE_POWER_LEVELS};
+
+    let mut state_map = StateMap::new();
+    state_map.insert(TYPE_POWER_LEVELS, "", "$pl1");
+
+    let mut state_map = state_map.into_indexed();
+    state_map.insert(TYPE_MEMBERSHIP, "@alice:example.com", "$alice");
+    state_map.insert("test", "a", "$test");
+
+    assert_eq!(
+        state_map.key_for_value(&"$pl1"),
+        Some(StateKeyRef::new(TYPE_POWER_LEVELS, ""))
+    );
+    assert_eq!(
+        state_map.key_for_value(&"$alice"),
+        Some(StateKeyRef::Member("@alice:example.com"))
+    );
+
+    // Replacing an entry unindexes the old value.
+    state_map.insert(TYPE_POWER_LEVELS, "", "$pl2");
+    state_map.insert(TYPE_POWER_LEVELS, "", "$pl2");
+    assert_eq!(state_map.key_for_value(&"$pl1"), None);
+    assert_eq!(
+        state_map.key_for_value(&"$pl2").map(StateKeyRef::as_tuple),
+        Some((TYPE_POWER_LEVELS, ""))
+    );
+
+    assert_eq!(
+        state_map.remove_by_value(&"$test"),
+        Some(StateKey::new("test", "a"))
+    );
+    assert_eq!(state_map.get("test", "a"), None);
+    assert_eq!(state_map.remove_by_value(&"$test"), None);
+
+    state_map.remove(TYPE_MEMBERSHIP, "@alice:example.com");
+    assert_eq!(state_map.key_for_value(&"$alice"), None);
+
+    state_map.add_or_remove("test", "b", "$b");
+    assert_eq!(
+        state_map.key_for_value(&"$b"),
+        Some(StateKeyRef::Other("test", "b"))
+    );
+    state_map.retain(|t, _, _| t != "test");
+    assert_eq!(state_map.key_for_value(&"$b"), None);
+
+    assert_eq!(state_map.len(), 1);
+    assert_eq!(
+        state_map.into_inner().get(TYPE_POWER_LEVELS, ""),
+        Some(&"$pl2")
+    );
+}
+
+#[test]
+fn reverse_index_heap_size_test() {
+    use {TYPE_MEMBERSHIP, TYPE_POWER_LEVELS};
+
+    let mut state_map = StateMap::new().into_indexed();
+    state_map.insert(TYPE_POWER_LEVELS, "", "$pl");
+
+    // Well known keys take no space beyond the table.
+    assert_eq!(state_map.index_heap_size(), table_size(&state_map.keys));
+
+    // Membership keys only store the user ID, while other keys store both the
+    // type and state key.
+    state_map.insert(TYPE_MEMBERSHIP, "@alice:example.com", "$alice");
+    state_map.insert("org.example.custom", "a", "$custom");
+    assert_eq!(
+        state_map.index_heap_size(),
+        table_size(&state_map.keys)
+            + "@alice:example.com".len()
+            + "org.example.custom".len()
+            + "a".len()
+    );
+}
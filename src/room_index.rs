@@ -0,0 +1,477 @@
+// Copyright 2018 New Vector Ltd
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not
+// use this file except in compliance with the License. You may obtain a copy of
+// the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS, WITHOUT
+// WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied. See the
+// License for the specific language governing permissions and limitations under
+// the License.
+
+//! A container for the state of many rooms, with indexes for queries that
+//! span rooms.
+
+use std::collections::hash_map::{Entry, RandomState};
+use std::collections::{HashMap, HashSet};
+use std::fmt::Debug;
+use std::hash::BuildHasher;
+
+use interner::{Interner, Symbol};
+use {Membership, MembershipEvent, StateMap, WellKnownEmptyKeys};
+use {TYPE_ALIASES, TYPE_MEMBERSHIP, TYPE_THIRD_PARTY_INVITE};
+
+/// Maps room IDs to their `StateMap`s, and keeps indexes of which rooms each
+/// user is joined to and which rooms have state of each type.
+///
+/// The room IDs, user IDs and types in the indexes are interned, so the
+/// indexes store each only once however many rooms it appears in. Strings are
+/// freed once nothing in the indexes refers to them, e.g. when a user has left
+/// all their rooms. The state maps of the rooms don't use the interner: each
+/// owns its keys as usual, so e.g. a user's ID is still copied into the state
+/// of every room they're in.
+///
+/// The rooms can only be changed through the index, so that it stays in sync.
+#[derive(Debug, Clone)]
+pub struct RoomStateIndex<E: Debug + Clone, S = RandomState> {
+    /// Holds a reference for each key of `rooms`, `joined_rooms` and
+    /// `rooms_by_type`. The sets only hold room IDs of rooms in `rooms`, so
+    /// don't need their own references.
+    interner: Interner<S>,
+    rooms: HashMap<Symbol, StateMap<E, S>, S>,
+    /// The rooms each user is joined to.
+    joined_rooms: HashMap<Symbol, HashSet<Symbol, S>, S>,
+    /// The rooms with at least one entry of each type.
+    rooms_by_type: HashMap<Symbol, HashSet<Symbol, S>, S>,
+    hash_builder: S,
+}
+
+impl<E> RoomStateIndex<E>
+where
+    E: Debug + Clone + MembershipEvent,
+{
+    pub fn new() -> RoomStateIndex<E> {
+        RoomStateIndex::with_hasher(RandomState::new())
+    }
+}
+
+impl<E> Default for RoomStateIndex<E>
+where
+    E: Debug + Clone + MembershipEvent,
+{
+    fn default() -> RoomStateIndex<E> {
+        RoomStateIndex::new()
+    }
+}
+
+/// Returns whether the map has any entries of the given type.
+fn has_type<E, S>(state_map: &StateMap<E, S>, t: &str) -> bool
+where
+    E: Debug + Clone,
+    S: BuildHasher,
+{
+    match t {
+        TYPE_MEMBERSHIP => !state_map.membership.is_empty(),
+        TYPE_ALIASES => !state_map.aliases.is_empty(),
+        TYPE_THIRD_PARTY_INVITE => !state_map.invites.is_empty(),
+        t => {
+            let well_known = match WellKnownEmptyKeys::from_str(t) {
+                Some(k) => state_map.well_known.contains_key(k),
+                None => false,
+            };
+            well_known || state_map.others.contains_key(t)
+        }
+    }
+}
+
+fn is_joined<E: MembershipEvent>(e: &E) -> bool {
+    e.membership() == Membership::Join
+}
+
+/// Whether the user is joined to the room with the given state.
+fn is_joined_member<E, S>(state_map: &StateMap<E, S>, user: &str) -> bool
+where
+    E: Debug + Clone + MembershipEvent,
+    S: BuildHasher + Clone,
+{
+    match state_map.get_membership(user) {
+        Some(e) => is_joined(e),
+        None => false,
+    }
+}
+
+/// Returns an iterator over the types the map has entries of. A type may be
+/// returned twice if it's well known but has entries with non-empty state
+/// keys.
+fn types<'a, E, S>(state_map: &'a StateMap<E, S>) -> impl Iterator<Item = &'a str>
+where
+    E: Debug + Clone,
+    S: BuildHasher,
+{
+    let buckets: [(&'a str, bool); 3] = [
+        (TYPE_MEMBERSHIP, state_map.membership.is_empty()),
+        (TYPE_ALIASES, state_map.aliases.is_empty()),
+        (TYPE_THIRD_PARTY_INVITE, state_map.invites.is_empty()),
+    ];
+
+    state_map
+        .well_known
+        .keys()
+        .map(|k| -> &'a str { k.as_str() })
+        .chain(
+            IntoIterator::into_iter(buckets)
+                .filter(|&(_, is_empty)| !is_empty)
+                .map(|(t, _)| t),
+        )
+        .chain(state_map.others.keys().map(|t| t as &str))
+}
+
+/// Adds `value` to the set for `key`, interning `key` if there isn't a set for
+/// it yet.
+fn add_to_set<S>(
+    sets: &mut HashMap<Symbol, HashSet<Symbol, S>, S>,
+    interner: &mut Interner<S>,
+    key: &str,
+    value: Symbol,
+    hash_builder: &S,
+) where
+    S: BuildHasher + Clone,
+{
+    if let Some(set) = interner.get(key).and_then(|key| sets.get_mut(&key)) {
+        set.insert(value);
+        return;
+    }
+
+    let mut set = HashSet::with_hasher(hash_builder.clone());
+    set.insert(value);
+    sets.insert(interner.intern(key), set);
+}
+
+/// Removes `value` from the set for `key`, dropping the set and releasing
+/// `key` if it ends up empty.
+fn remove_from_set<S>(
+    sets: &mut HashMap<Symbol, HashSet<Symbol, S>, S>,
+    interner: &mut Interner<S>,
+    key: &str,
+    value: Symbol,
+) where
+    S: BuildHasher,
+{
+    let key = match interner.get(key) {
+        Some(key) => key,
+        None => return,
+    };
+
+    if let Entry::Occupied(mut entry) = sets.entry(key) {
+        entry.get_mut().remove(&value);
+        if entry.get().is_empty() {
+            entry.remove();
+            interner.release(key);
+        }
+    }
+}
+
+impl<E, S> RoomStateIndex<E, S>
+where
+    E: Debug + Clone + MembershipEvent,
+    S: BuildHasher + Clone,
+{
+    /// Creates an empty index which will use the given hash builder for its
+    /// own maps and for any rooms it creates.
+    pub fn with_hasher(hash_builder: S) -> RoomStateIndex<E, S> {
+        RoomStateIndex {
+            interner: Interner::with_hasher(hash_builder.clone()),
+            rooms: HashMap::with_hasher(hash_builder.clone()),
+            joined_rooms: HashMap::with_hasher(hash_builder.clone()),
+            rooms_by_type: HashMap::with_hasher(hash_builder.clone()),
+            hash_builder,
+        }
+    }
+
+    /// The number of rooms in the index.
+    pub fn len(&self) -> usize {
+        self.rooms.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rooms.is_empty()
+    }
+
+    pub fn room(&self, room_id: &str) -> Option<&StateMap<E, S>> {
+        self.interner
+            .get(room_id)
+            .and_then(|room| self.rooms.get(&room))
+    }
+
+    /// Returns an iterator over the room IDs and state of all rooms.
+    pub fn rooms(&self) -> impl Iterator<Item = (&str, &StateMap<E, S>)> {
+        self.rooms
+            .iter()
+            .map(move |(&room, state_map)| (self.interner.resolve(room), state_map))
+    }
+
+    /// Adds the state of a room to the index, returning the state it replaces.
+    pub fn insert_room(
+        &mut self,
+        room_id: &str,
+        state_map: StateMap<E, S>,
+    ) -> Option<StateMap<E, S>> {
+        let old = self.remove_room(room_id);
+
+        let room = self.interner.intern(room_id);
+        for t in types(&state_map) {
+            add_to_set(
+                &mut self.rooms_by_type,
+                &mut self.interner,
+                t,
+                room,
+                &self.hash_builder,
+            );
+        }
+        for (user, e) in state_map.iter_members() {
+            if is_joined(e) {
+                add_to_set(
+                    &mut self.joined_rooms,
+                    &mut self.interner,
+                    user,
+                    room,
+                    &self.hash_builder,
+                );
+            }
+        }
+
+        self.rooms.insert(room, state_map);
+        old
+    }
+
+    /// Removes a room from the index, returning its state.
+    pub fn remove_room(&mut self, room_id: &str) -> Option<StateMap<E, S>> {
+        let room = self.interner.get(room_id)?;
+        let state_map = self.rooms.remove(&room)?;
+
+        for t in types(&state_map) {
+            remove_from_set(&mut self.rooms_by_type, &mut self.interner, t, room);
+        }
+        for (user, e) in state_map.iter_members() {
+            if is_joined(e) {
+                remove_from_set(&mut self.joined_rooms, &mut self.interner, user, room);
+            }
+        }
+
+        self.interner.release(room);
+        Some(state_map)
+    }
+
+    /// Inserts an entry into the state of a room, adding the room if it isn't
+    /// already in the index.
+    pub fn insert(&mut self, room_id: &str, t: &str, s: &str, value: E) -> Option<E> {
+        let room = match self.interner.get(room_id) {
+            Some(room) if self.rooms.contains_key(&room) => room,
+            _ => {
+                let room = self.interner.intern(room_id);
+                let state_map = StateMap::with_hasher(self.hash_builder.clone());
+                self.rooms.insert(room, state_map);
+                room
+            }
+        };
+
+        let old = self
+            .rooms
+            .get_mut(&room)
+            .and_then(|state_map| state_map.insert(t, s, value));
+
+        add_to_set(
+            &mut self.rooms_by_type,
+            &mut self.interner,
+            t,
+            room,
+            &self.hash_builder,
+        );
+        if t == TYPE_MEMBERSHIP {
+            self.update_membership(room, s);
+        }
+
+        old
+    }
+
+    /// Removes an entry from the state of a room. The room stays in the index
+    /// even if it ends up empty.
+    pub fn remove(&mut self, room_id: &str, t: &str, s: &str) -> Option<E> {
+        let room = self.interner.get(room_id)?;
+        let old = self.rooms.get_mut(&room)?.remove(t, s)?;
+
+        if !has_type(&self.rooms[&room], t) {
+            remove_from_set(&mut self.rooms_by_type, &mut self.interner, t, room);
+        }
+        if t == TYPE_MEMBERSHIP {
+            self.update_membership(room, s);
+        }
+
+        Some(old)
+    }
+
+    /// Updates the joined rooms of the user to match their current membership
+    /// of the room.
+    fn update_membership(&mut self, room: Symbol, user: &str) {
+        if is_joined_member(&self.rooms[&room], user) {
+            add_to_set(
+                &mut self.joined_rooms,
+                &mut self.interner,
+                user,
+                room,
+                &self.hash_builder,
+            );
+        } else {
+            remove_from_set(&mut self.joined_rooms, &mut self.interner, user, room);
+        }
+    }
+
+    /// Returns an iterator over the IDs of the rooms the user is joined to, in
+    /// arbitrary order.
+    pub fn joined_rooms(&self, user: &str) -> impl Iterator<Item = &str> {
+        self.interner
+            .get(user)
+            .and_then(|user| self.joined_rooms.get(&user))
+            .into_iter()
+            .flat_map(|rooms| rooms.iter())
+            .map(move |&room| self.interner.resolve(room))
+    }
+
+    /// Returns an iterator over the IDs of the rooms with any state of the
+    /// given type, in arbitrary order.
+    pub fn rooms_with_type(&self, t: &str) -> impl Iterator<Item = &str> {
+        self.interner
+            .get(t)
+            .and_then(|t| self.rooms_by_type.get(&t))
+            .into_iter()
+            .flat_map(|rooms| rooms.iter())
+            .map(move |&room| self.interner.resolve(room))
+    }
+
+    /// Returns the users joined to both rooms, in arbitrary order.
+    pub fn users_in_common(&self, room_a: &str, room_b: &str) -> Vec<&str> {
+        let (a, b) = match (self.room(room_a), self.room(room_b)) {
+            (Some(a), Some(b)) => (a, b),
+            _ => return Vec::new(),
+        };
+
+        // Scan the smaller of the two rooms.
+        let (small, large) = if a.membership.len() <= b.membership.len() {
+            (a, b)
+        } else {
+            (b, a)
+        };
+
+        small
+            .iter_members()
+            .filter(|&(user, e)| is_joined(e) && is_joined_member(large, user))
+            .map(|(user, _)| user)
+            .collect()
+    }
+}
+
+#[test]
+fn room_state_index_test() {
+    use TYPE_TOPIC;
+
+    #[derive(Debug, Clone)]
+    struct Event(Membership);
+
+    impl MembershipEvent for Event {
+        fn membership(&self) -> Membership {
+            self.0
+        }
+
+        fn stream_ordering(&self) -> i64 {
+            0
+        }
+    }
+
+    fn sorted<'a, I: Iterator<Item = &'a str>>(iter: I) -> Vec<&'a str> {
+        let mut v: Vec<_> = iter.collect();
+        v.sort();
+        v
+    }
+
+    let join = || Event(Membership::Join);
+    let leave = || Event(Membership::Leave);
+
+    let mut state_map = StateMap::new();
+    state_map.insert(TYPE_MEMBERSHIP, "@alice:example.com", join());
+    state_map.insert(TYPE_MEMBERSHIP, "@bob:example.com", join());
+    state_map.insert(TYPE_TOPIC, "", join());
+
+    let mut index = RoomStateIndex::new();
+    index.insert_room("!a:example.com", state_map);
+    index.insert(
+        "!b:example.com",
+        TYPE_MEMBERSHIP,
+        "@alice:example.com",
+        join(),
+    );
+    index.insert(
+        "!b:example.com",
+        TYPE_MEMBERSHIP,
+        "@bob:example.com",
+        leave(),
+    );
+    index.insert(
+        "!b:example.com",
+        TYPE_MEMBERSHIP,
+        "@carol:example.com",
+        join(),
+    );
+
+    assert_eq!(index.len(), 2);
+    assert_eq!(
+        sorted(index.joined_rooms("@alice:example.com")),
+        vec!["!a:example.com", "!b:example.com"]
+    );
+    assert_eq!(
+        sorted(index.joined_rooms("@bob:example.com")),
+        vec!["!a:example.com"]
+    );
+    assert_eq!(
+        sorted(index.rooms_with_type(TYPE_TOPIC)),
+        vec!["!a:example.com"]
+    );
+    assert_eq!(
+        index.users_in_common("!a:example.com", "!b:example.com"),
+        vec!["@alice:example.com"]
+    );
+
+    // Membership changes update the indexes.
+    index.insert(
+        "!a:example.com",
+        TYPE_MEMBERSHIP,
+        "@alice:example.com",
+        leave(),
+    );
+    assert_eq!(
+        sorted(index.joined_rooms("@alice:example.com")),
+        vec!["!b:example.com"]
+    );
+    assert!(index
+        .users_in_common("!a:example.com", "!b:example.com")
+        .is_empty());
+
+    index.remove("!a:example.com", TYPE_TOPIC, "");
+    assert_eq!(index.rooms_with_type(TYPE_TOPIC).count(), 0);
+
+    let state_map = index.remove_room("!b:example.com").unwrap();
+    assert_eq!(state_map.len(), 3);
+    assert_eq!(index.joined_rooms("@carol:example.com").count(), 0);
+
+    // Nothing refers to Carol, the topic type or the removed room any more.
+    assert_eq!(index.interner.get("@carol:example.com"), None);
+    assert_eq!(index.interner.get(TYPE_TOPIC), None);
+    assert_eq!(index.interner.get("!b:example.com"), None);
+    assert!(index.interner.get("@bob:example.com").is_some());
+    assert_eq!(
+        sorted(index.rooms_with_type(TYPE_MEMBERSHIP)),
+        vec!["!a:example.com"]
+    );
+}
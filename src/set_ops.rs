@@ -0,0 +1,238 @@
+// Copyright 2018 New Vector Ltd
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not
+// use this file except in compliance with the License. You may obtain a copy of
+// the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS, WITHOUT
+// WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied. See the
+// License for the specific language governing permissions and limitations under
+// the License.
+
+//! Set operations and merging of `StateMap`s by key.
+//!
+//! These all work bucket by bucket, so entries are only ever compared against
+//! the matching bucket of the other map rather than being looked up by type
+//! and state key.
+
+use std::collections::{hash_map, HashMap};
+use std::fmt::Debug;
+use std::hash::{BuildHasher, Hash};
+
+use small_map::SmallMap;
+use {StateMap, TYPE_ALIASES, TYPE_MEMBERSHIP, TYPE_THIRD_PARTY_INVITE};
+
+/// Moves all entries of `theirs` into `ours`, calling `f` to resolve keys
+/// that exist in both. If `f` returns `None` the key is removed.
+///
+/// Conflicts are resolved in place through the entry, so the key is only
+/// looked up once.
+fn merge_bucket<K, E, S, F>(ours: &mut HashMap<K, E, S>, theirs: HashMap<K, E, S>, mut f: F)
+where
+    K: Eq + Hash,
+    E: Clone,
+    S: BuildHasher,
+    F: FnMut(&K, E, E) -> Option<E>,
+{
+    for (k, their_value) in theirs {
+        match ours.entry(k) {
+            hash_map::Entry::Occupied(mut o) => {
+                let our_value = o.get().clone();
+                match f(o.key(), our_value, their_value) {
+                    Some(value) => *o.get_mut() = value,
+                    None => {
+                        o.remove();
+                    }
+                }
+            }
+            hash_map::Entry::Vacant(v) => {
+                v.insert(their_value);
+            }
+        }
+    }
+}
+
+/// As [`merge_bucket`], but for the per type maps of uncommon types, using
+/// `hash_builder` if `ours` grows into a `HashMap`.
+fn merge_type_map<E, S, F>(
+    ours: &mut SmallMap<E, S>,
+    theirs: SmallMap<E, S>,
+    hash_builder: &S,
+    mut f: F,
+) where
+    E: Clone,
+    S: BuildHasher + Clone,
+    F: FnMut(&str, E, E) -> Option<E>,
+{
+    for (k, their_value) in theirs {
+        match ours.get_mut(&k) {
+            Some(slot) => {
+                if let Some(value) = f(&k, slot.clone(), their_value) {
+                    *slot = value;
+                    continue;
+                }
+            }
+            None => {
+                ours.insert(k, their_value, hash_builder);
+                continue;
+            }
+        }
+        ours.remove(&k);
+    }
+}
+
+impl<E, S> StateMap<E, S>
+where
+    E: Debug + Clone,
+    S: BuildHasher + Clone,
+{
+    /// Moves all entries from `other` into this map. For keys that exist in
+    /// both maps `f` is called with the key, our value and their value, and
+    /// its result is stored, or the key removed if it returns `None`.
+    ///
+    /// Any types marked as partial in `other` are marked as partial here too.
+    pub fn merge_with<F>(&mut self, other: StateMap<E, S>, mut f: F)
+    where
+        F: FnMut((&str, &str), E, E) -> Option<E>,
+    {
+        for (k, theirs) in other.well_known {
+            let value = match self.well_known.remove(k) {
+                Some(ours) => f((k.as_str(), ""), ours, theirs),
+                None => Some(theirs),
+            };
+            if let Some(value) = value {
+                self.well_known.insert(k, value);
+            }
+        }
+        merge_bucket(&mut self.membership, other.membership, |u, a, b| {
+            f((TYPE_MEMBERSHIP, u), a, b)
+        });
+        merge_bucket(&mut self.aliases, other.aliases, |s, a, b| {
+            f((TYPE_ALIASES, s), a, b)
+        });
+        merge_bucket(&mut self.invites, other.invites, |t, a, b| {
+            f((TYPE_THIRD_PARTY_INVITE, t), a, b)
+        });
+
+        for t in other.partial_types {
+            self.mark_partial(&t);
+        }
+
+        let hash_builder = self.others.hasher().clone();
+        for (t, theirs) in other.others {
+            let is_empty = match self.others.get_mut(&t) {
+                Some(ours) => {
+                    merge_type_map(ours, theirs, &hash_builder, |s, a, b| f((&t, s), a, b));
+                    ours.is_empty()
+                }
+                None => {
+                    self.others.insert(t, theirs);
+                    continue;
+                }
+            };
+            if is_empty {
+                self.others.remove(&t);
+            }
+        }
+    }
+
+    /// Returns a map with all entries from both maps. Where a key exists in
+    /// both the value from `self` is kept.
+    pub fn union(mut self, other: StateMap<E, S>) -> StateMap<E, S> {
+        self.merge_with(other, |_, ours, _| Some(ours));
+        self
+    }
+
+    /// Returns a map with the entries from `self` whose keys also exist in
+    /// `other`.
+    pub fn intersection(mut self, other: &StateMap<E, S>) -> StateMap<E, S> {
+        self.retain_by_other(other, true);
+        self
+    }
+
+    /// Returns a map with the entries from `self` whose keys do not exist in
+    /// `other`.
+    pub fn difference(mut self, other: &StateMap<E, S>) -> StateMap<E, S> {
+        self.retain_by_other(other, false);
+        self
+    }
+
+    /// Retains the entries whose presence in `other` matches `present`.
+    fn retain_by_other(&mut self, other: &StateMap<E, S>, present: bool) {
+        self.well_known
+            .retain(|k, _| other.well_known.contains_key(k) == present);
+        self.membership
+            .retain(|u, _| other.membership.contains_key(u) == present);
+        self.aliases
+            .retain(|s, _| other.aliases.contains_key(s) == present);
+        self.invites
+            .retain(|t, _| other.invites.contains_key(t) == present);
+
+        self.others.retain(|t, ours| {
+            match other.others.get(t) {
+                Some(theirs) => ours.retain(|s, _| theirs.contains_key(s) == present),
+                None if present => ours.clear(),
+                None => {}
+            }
+            !ours.is_empty()
+        });
+    }
+}
+
+#[test]
+fn set_ops_test() {
+    use TYPE_POWER_LEVELS;
+
+    let a: StateMap<_> = vec![
+        ((TYPE_POWER_LEVELS, ""), 1),
+        ((TYPE_MEMBERSHIP, "@alice:example.com"), 2),
+        (("test", "a"), 3),
+        (("test", "b"), 4),
+    ]
+    .into_iter()
+    .collect();
+
+    let b: StateMap<_> = vec![
+        ((TYPE_POWER_LEVELS, ""), 10),
+        ((TYPE_MEMBERSHIP, "@bob:example.com"), 20),
+        (("test", "a"), 30),
+        (("other", ""), 40),
+    ]
+    .into_iter()
+    .collect();
+
+    let union = a.clone().union(b.clone());
+    assert_eq!(union.len(), 6);
+    assert_eq!(union.get(TYPE_POWER_LEVELS, ""), Some(&1));
+    assert_eq!(union.get(TYPE_MEMBERSHIP, "@bob:example.com"), Some(&20));
+    assert_eq!(union.get("other", ""), Some(&40));
+
+    let intersection = a.clone().intersection(&b);
+    assert_eq!(intersection.len(), 2);
+    assert_eq!(intersection.get(TYPE_POWER_LEVELS, ""), Some(&1));
+    assert_eq!(intersection.get("test", "a"), Some(&3));
+
+    let difference = a.clone().difference(&b);
+    assert_eq!(difference.len(), 2);
+    assert_eq!(
+        difference.get(TYPE_MEMBERSHIP, "@alice:example.com"),
+        Some(&2)
+    );
+    assert_eq!(difference.get("test", "b"), Some(&4));
+
+    let mut merged = a;
+    merged.merge_with(b, |(t, _), ours, theirs| {
+        if t == "test" {
+            None
+        } else {
+            Some(ours + theirs)
+        }
+    });
+    assert_eq!(merged.len(), 5);
+    assert_eq!(merged.get(TYPE_POWER_LEVELS, ""), Some(&11));
+    assert_eq!(merged.get("test", "a"), None);
+    assert_eq!(merged.get("test", "b"), Some(&4));
+}
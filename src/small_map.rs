@@ -0,0 +1,436 @@
+// Copyright 2018 New Vector Ltd
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not
+// use this file except in compliance with the License. You may obtain a copy of
+// the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS, WITHOUT
+// WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied. See the
+// License for the specific language governing permissions and limitations under
+// the License.
+
+//! The per type maps used for uncommon types, which usually only have one
+//! entry (with an empty state key).
+
+use std::collections::{hash_map, HashMap};
+use std::fmt::{self, Debug};
+use std::hash::BuildHasher;
+use std::mem;
+use std::slice;
+use std::vec;
+
+/// The number of entries a `SmallMap` holds before switching to a `HashMap`.
+const MAX_SMALL_LEN: usize = 8;
+
+#[derive(Clone)]
+enum Repr<E, S> {
+    /// A list of entries that is searched linearly.
+    Small(Vec<(String, E)>),
+    /// Boxed so that the common small case isn't padded out to the size of a
+    /// `HashMap`.
+    #[allow(clippy::box_collection)]
+    Large(Box<HashMap<String, E, S>>),
+}
+
+/// A map from state key to value, which is stored as a short list until it
+/// grows beyond `MAX_SMALL_LEN` entries.
+///
+/// A single entry takes one small allocation, rather than a full hash table.
+/// The map doesn't store a hasher of its own: methods that may need to grow
+/// it into a `HashMap` take one from the caller, i.e. the hasher of the
+/// `StateMap`.
+#[derive(Clone)]
+pub(crate) struct SmallMap<E, S> {
+    repr: Repr<E, S>,
+}
+
+impl<E, S> SmallMap<E, S> {
+    pub fn new() -> SmallMap<E, S> {
+        SmallMap {
+            repr: Repr::Small(Vec::new()),
+        }
+    }
+
+    /// Creates a map holding a single entry.
+    pub fn with_entry(key: String, value: E) -> SmallMap<E, S> {
+        SmallMap {
+            repr: Repr::Small(vec![(key, value)]),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self.repr {
+            Repr::Small(ref v) => v.len(),
+            Repr::Large(ref h) => h.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn retain<F>(&mut self, mut f: F)
+    where
+        F: FnMut(&str, &mut E) -> bool,
+    {
+        match self.repr {
+            Repr::Small(ref mut v) => v.retain_mut(|&mut (ref k, ref mut e)| f(k, e)),
+            Repr::Large(ref mut h) => h.retain(|k, e| f(k, e)),
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.repr = Repr::Small(Vec::new());
+    }
+
+    pub fn iter(&self) -> Iter<'_, E> {
+        match self.repr {
+            Repr::Small(ref v) => Iter::Small(v.iter()),
+            Repr::Large(ref h) => Iter::Large(h.iter()),
+        }
+    }
+
+    pub fn iter_mut(&mut self) -> IterMut<'_, E> {
+        match self.repr {
+            Repr::Small(ref mut v) => IterMut::Small(v.iter_mut()),
+            Repr::Large(ref mut h) => IterMut::Large(h.iter_mut()),
+        }
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &String> {
+        self.iter().map(|(k, _)| k)
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &E> {
+        self.iter().map(|(_, e)| e)
+    }
+
+    pub fn values_mut(&mut self) -> impl Iterator<Item = &mut E> {
+        self.iter_mut().map(|(_, e)| e)
+    }
+
+    /// Estimates the number of bytes allocated for the entries, ignoring any
+    /// heap allocations owned by the keys and values.
+    pub fn allocated_size(&self) -> usize {
+        match self.repr {
+            Repr::Small(ref v) => v.capacity() * mem::size_of::<(String, E)>(),
+            // This assumes one control byte per slot, as in `heap_size`.
+            Repr::Large(ref h) => {
+                mem::size_of::<HashMap<String, E, S>>()
+                    + h.capacity() * (mem::size_of::<(String, E)>() + 1)
+            }
+        }
+    }
+}
+
+impl<E, S> SmallMap<E, S>
+where
+    S: BuildHasher,
+{
+    pub fn get(&self, key: &str) -> Option<&E> {
+        match self.repr {
+            Repr::Small(ref v) => v.iter().find(|(k, _)| k == key).map(|(_, e)| e),
+            Repr::Large(ref h) => h.get(key),
+        }
+    }
+
+    pub fn get_mut(&mut self, key: &str) -> Option<&mut E> {
+        match self.repr {
+            Repr::Small(ref mut v) => v
+                .iter_mut()
+                .find(|&&mut (ref k, _)| k == key)
+                .map(|&mut (_, ref mut e)| e),
+            Repr::Large(ref mut h) => h.get_mut(key),
+        }
+    }
+
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.get(key).is_some()
+    }
+
+    pub fn remove(&mut self, key: &str) -> Option<E> {
+        match self.repr {
+            Repr::Small(ref mut v) => {
+                let idx = v.iter().position(|(k, _)| k == key)?;
+                Some(v.swap_remove(idx).1)
+            }
+            Repr::Large(ref mut h) => h.remove(key),
+        }
+    }
+}
+
+impl<E, S> SmallMap<E, S>
+where
+    S: BuildHasher + Clone,
+{
+    /// Inserts the entry, returning the previous value if there was one.
+    pub fn insert(&mut self, key: String, value: E, hash_builder: &S) -> Option<E> {
+        if let Some(e) = self.get_mut(&key) {
+            return Some(mem::replace(e, value));
+        }
+
+        self.insert_new(key, value, hash_builder);
+        None
+    }
+
+    /// As [`SmallMap::insert`], except that the key is only copied into a new
+    /// `String` if there isn't already an entry for it.
+    pub fn insert_str(&mut self, key: &str, value: E, hash_builder: &S) -> Option<E> {
+        if let Some(e) = self.get_mut(key) {
+            return Some(mem::replace(e, value));
+        }
+
+        self.insert_new(key.into(), value, hash_builder);
+        None
+    }
+
+    /// Inserts an entry that is known to not be in the map, growing into a
+    /// `HashMap` with a clone of `hash_builder` if needed.
+    fn insert_new(&mut self, key: String, value: E, hash_builder: &S) -> &mut E {
+        let grow = match self.repr {
+            Repr::Small(ref v) => v.len() >= MAX_SMALL_LEN,
+            Repr::Large(_) => false,
+        };
+
+        if grow {
+            let mut h = HashMap::with_capacity_and_hasher(MAX_SMALL_LEN * 2, hash_builder.clone());
+            if let Repr::Small(ref mut v) = self.repr {
+                h.extend(v.drain(..));
+            }
+            self.repr = Repr::Large(Box::new(h));
+        }
+
+        match self.repr {
+            Repr::Small(ref mut v) => {
+                // Most types only ever have one entry, so don't let the first
+                // push allocate room for several.
+                if v.capacity() == 0 {
+                    v.reserve_exact(1);
+                }
+                v.push((key, value));
+                let last = v.len() - 1;
+                &mut v[last].1
+            }
+            Repr::Large(ref mut h) => h.entry(key).or_insert(value),
+        }
+    }
+
+    /// Gets the value for the key, inserting the result of `f` (and copying
+    /// the key) if there isn't one.
+    pub fn get_or_insert_with<F>(&mut self, key: &str, f: F, hash_builder: &S) -> &mut E
+    where
+        F: FnOnce() -> E,
+    {
+        if !self.contains_key(key) {
+            return self.insert_new(key.into(), f(), hash_builder);
+        }
+        self.get_mut(key).expect("key is in map")
+    }
+
+    /// Converts the map into one with a different value type by applying `f`
+    /// to each entry, stopping at the first error.
+    pub fn try_map<F, Err, M>(self, mut f: M) -> Result<SmallMap<F, S>, Err>
+    where
+        M: FnMut(&str, E) -> Result<F, Err>,
+    {
+        let repr = match self.repr {
+            Repr::Small(v) => {
+                let mut mapped = Vec::with_capacity(v.len());
+                for (k, e) in v {
+                    let value = f(&k, e)?;
+                    mapped.push((k, value));
+                }
+                Repr::Small(mapped)
+            }
+            Repr::Large(h) => {
+                let mut mapped = HashMap::with_capacity_and_hasher(h.len(), h.hasher().clone());
+                for (k, e) in *h {
+                    let value = f(&k, e)?;
+                    mapped.insert(k, value);
+                }
+                Repr::Large(Box::new(mapped))
+            }
+        };
+
+        Ok(SmallMap { repr })
+    }
+}
+
+impl<E, S> SmallMap<E, S>
+where
+    E: Clone + PartialEq,
+    S: BuildHasher + Clone,
+{
+    /// Implements [`StateMap::add_or_remove`] for the map, only copying the
+    /// key if there isn't already an entry for it.
+    ///
+    /// [`StateMap::add_or_remove`]: ::StateMap::add_or_remove
+    pub fn add_or_remove(&mut self, key: &str, value: &E, hash_builder: &S) -> Option<E> {
+        match self.get(key) {
+            Some(e) if e != value => self.remove(key),
+            Some(_) => None,
+            None => {
+                self.insert_new(key.into(), value.clone(), hash_builder);
+                None
+            }
+        }
+    }
+}
+
+#[cfg(feature = "rayon")]
+impl<E, S> SmallMap<E, S>
+where
+    E: Sync,
+    S: BuildHasher + Sync,
+{
+    pub fn par_iter(&self) -> impl ::rayon::iter::ParallelIterator<Item = (&String, &E)> {
+        use rayon::iter::{Either, IntoParallelRefIterator, ParallelIterator};
+
+        match self.repr {
+            Repr::Small(ref v) => Either::Left(v.par_iter().map(|(k, e)| (k, e))),
+            Repr::Large(ref h) => Either::Right(h.par_iter()),
+        }
+    }
+}
+
+impl<E, S> PartialEq for SmallMap<E, S>
+where
+    E: PartialEq,
+    S: BuildHasher,
+{
+    fn eq(&self, other: &SmallMap<E, S>) -> bool {
+        self.len() == other.len() && self.iter().all(|(k, e)| other.get(k) == Some(e))
+    }
+}
+
+impl<E: Debug, S> Debug for SmallMap<E, S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
+
+impl<E, S> IntoIterator for SmallMap<E, S> {
+    type Item = (String, E);
+    type IntoIter = IntoIter<E>;
+
+    fn into_iter(self) -> IntoIter<E> {
+        match self.repr {
+            Repr::Small(v) => IntoIter::Small(v.into_iter()),
+            Repr::Large(h) => IntoIter::Large((*h).into_iter()),
+        }
+    }
+}
+
+pub(crate) enum Iter<'a, E: 'a> {
+    Small(slice::Iter<'a, (String, E)>),
+    Large(hash_map::Iter<'a, String, E>),
+}
+
+impl<'a, E> Iterator for Iter<'a, E> {
+    type Item = (&'a String, &'a E);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match *self {
+            Iter::Small(ref mut it) => it.next().map(|(k, e)| (k, e)),
+            Iter::Large(ref mut it) => it.next(),
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        match *self {
+            Iter::Small(ref it) => it.size_hint(),
+            Iter::Large(ref it) => it.size_hint(),
+        }
+    }
+}
+
+pub(crate) enum IterMut<'a, E: 'a> {
+    Small(slice::IterMut<'a, (String, E)>),
+    Large(hash_map::IterMut<'a, String, E>),
+}
+
+impl<'a, E> Iterator for IterMut<'a, E> {
+    type Item = (&'a String, &'a mut E);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match *self {
+            IterMut::Small(ref mut it) => it.next().map(|&mut (ref k, ref mut e)| (k, e)),
+            IterMut::Large(ref mut it) => it.next(),
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        match *self {
+            IterMut::Small(ref it) => it.size_hint(),
+            IterMut::Large(ref it) => it.size_hint(),
+        }
+    }
+}
+
+pub(crate) enum IntoIter<E> {
+    Small(vec::IntoIter<(String, E)>),
+    Large(hash_map::IntoIter<String, E>),
+}
+
+impl<E> Iterator for IntoIter<E> {
+    type Item = (String, E);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match *self {
+            IntoIter::Small(ref mut it) => it.next(),
+            IntoIter::Large(ref mut it) => it.next(),
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        match *self {
+            IntoIter::Small(ref it) => it.size_hint(),
+            IntoIter::Large(ref it) => it.size_hint(),
+        }
+    }
+}
+
+#[test]
+fn small_map_test() {
+    use std::collections::hash_map::RandomState;
+
+    let hash_builder = RandomState::new();
+
+    // The map is no bigger than the list it usually holds, and a single entry
+    // only allocates room for itself.
+    assert_eq!(
+        mem::size_of::<SmallMap<usize, RandomState>>(),
+        mem::size_of::<Vec<(String, usize)>>()
+    );
+    let mut map = SmallMap::new();
+    assert_eq!(map.insert_str("", 0, &hash_builder), None);
+    assert_eq!(map.allocated_size(), mem::size_of::<(String, usize)>());
+    assert_eq!(map.insert_str("", 1, &hash_builder), Some(0));
+    assert_eq!(map.len(), 1);
+
+    // Grow past the threshold, so that the map switches to a hash map.
+    for i in 0..MAX_SMALL_LEN * 2 {
+        map.insert(i.to_string(), i + 10, &hash_builder);
+    }
+    assert_eq!(map.len(), MAX_SMALL_LEN * 2 + 1);
+    assert_eq!(map.get(""), Some(&1));
+    assert_eq!(map.get("3"), Some(&13));
+    assert_eq!(map.remove("3"), Some(13));
+    assert_eq!(map.get("3"), None);
+
+    let mut small = SmallMap::with_entry("a".to_string(), 0);
+    assert_eq!(small.allocated_size(), mem::size_of::<(String, usize)>());
+    small.clear();
+    for (k, e) in map.clone() {
+        small.insert(k, e, &hash_builder);
+    }
+    assert_eq!(small, map);
+
+    map.retain(|k, _| k.is_empty());
+    assert_eq!(map.len(), 1);
+    assert_eq!(*map.get_or_insert_with("new", || 2, &hash_builder), 2);
+    assert_eq!(map.iter().count(), 2);
+}
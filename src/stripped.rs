@@ -0,0 +1,152 @@
+// Copyright 2018 New Vector Ltd
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not
+// use this file except in compliance with the License. You may obtain a copy of
+// the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS, WITHOUT
+// WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied. See the
+// License for the specific language governing permissions and limitations under
+// the License.
+
+//! Extraction of the stripped state that is sent along with invites and knocks.
+
+use std::fmt::Debug;
+use std::hash::BuildHasher;
+
+use {StateMap, WellKnownEmptyKeys, TYPE_ALIASES, TYPE_MEMBERSHIP, TYPE_THIRD_PARTY_INVITE};
+
+/// The well known types that are always included in stripped state.
+pub const STRIPPED_STATE_TYPES: &[WellKnownEmptyKeys] = &[
+    WellKnownEmptyKeys::Create,
+    WellKnownEmptyKeys::JoinRules,
+    WellKnownEmptyKeys::Name,
+    WellKnownEmptyKeys::Avatar,
+    WellKnownEmptyKeys::CanonicalAliases,
+    WellKnownEmptyKeys::Encryption,
+    WellKnownEmptyKeys::Topic,
+];
+
+/// Configures which state is included by [`StateMap::stripped_state`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StrippedStateConfig {
+    /// Types to include in addition to [`STRIPPED_STATE_TYPES`]. All entries
+    /// of these types are included, whatever their state key.
+    pub additional_types: Vec<String>,
+}
+
+/// An event that can be converted into the stripped form sent to servers
+/// and clients that aren't in the room, i.e. only its type, state key,
+/// sender and content.
+pub trait StrippedEvent {
+    type Stripped;
+
+    fn to_stripped(&self) -> Self::Stripped;
+}
+
+impl<E, S> StateMap<E, S>
+where
+    E: Debug + Clone,
+    S: BuildHasher + Clone,
+{
+    /// Returns the subset of the state to include in an invite or knock sent
+    /// by `sender`, i.e. the [`STRIPPED_STATE_TYPES`], the membership of
+    /// `sender` and any additional types in `config`.
+    pub fn stripped_state(&self, config: &StrippedStateConfig, sender: &str) -> StateMap<E, S> {
+        let mut stripped = StateMap::with_hasher(self.hasher().clone());
+
+        for &key in STRIPPED_STATE_TYPES {
+            if let Some(e) = self.well_known.get(key) {
+                stripped.well_known.insert(key, e.clone());
+            }
+        }
+
+        if let Some(e) = self.membership.get(sender) {
+            stripped.membership.insert(sender.to_string(), e.clone());
+        }
+
+        for t in &config.additional_types {
+            match t.as_str() {
+                TYPE_MEMBERSHIP => stripped.membership.extend(self.membership.clone()),
+                TYPE_ALIASES => stripped.aliases.extend(self.aliases.clone()),
+                TYPE_THIRD_PARTY_INVITE => stripped.invites.extend(self.invites.clone()),
+                t => {
+                    if let Some(key) = WellKnownEmptyKeys::from_str(t) {
+                        if let Some(e) = self.well_known.get(key) {
+                            stripped.well_known.insert(key, e.clone());
+                        }
+                    }
+
+                    if let Some(h) = self.others.get(t) {
+                        for (s, e) in h.iter() {
+                            stripped.insert(t, s, e.clone());
+                        }
+                    }
+                }
+            }
+        }
+
+        stripped
+    }
+}
+
+impl<E, S> StateMap<E, S>
+where
+    E: Debug + Clone + StrippedEvent,
+    S: BuildHasher + Clone,
+{
+    /// Returns the stripped form of the events in [`StateMap::stripped_state`],
+    /// ready to be included in an invite or knock.
+    pub fn stripped_events(&self, config: &StrippedStateConfig, sender: &str) -> Vec<E::Stripped> {
+        self.stripped_state(config, sender)
+            .values()
+            .map(StrippedEvent::to_stripped)
+            .collect()
+    }
+}
+
+#[test]
+fn stripped_state_test() {
+    use {TYPE_CREATE, TYPE_NAME, TYPE_POWER_LEVELS};
+
+    #[derive(Debug, Clone)]
+    struct Event(&'static str);
+
+    impl StrippedEvent for Event {
+        type Stripped = String;
+
+        fn to_stripped(&self) -> String {
+            format!("stripped {}", self.0)
+        }
+    }
+
+    let mut state_map = StateMap::new();
+    state_map.insert(TYPE_CREATE, "", Event("create"));
+    state_map.insert(TYPE_NAME, "", Event("name"));
+    state_map.insert(TYPE_POWER_LEVELS, "", Event("power_levels"));
+    state_map.insert(TYPE_MEMBERSHIP, "@alice:example.com", Event("alice"));
+    state_map.insert(TYPE_MEMBERSHIP, "@bob:example.com", Event("bob"));
+    state_map.insert("m.space.parent", "!space:example.com", Event("parent"));
+    state_map.insert("test", "a", Event("test"));
+
+    let config = StrippedStateConfig {
+        additional_types: vec!["m.space.parent".to_string()],
+    };
+
+    let stripped = state_map.stripped_state(&config, "@alice:example.com");
+    assert_eq!(stripped.len(), 4);
+    assert!(stripped.contains_key(TYPE_CREATE, ""));
+    assert!(stripped.contains_key(TYPE_NAME, ""));
+    assert!(stripped.contains_key(TYPE_MEMBERSHIP, "@alice:example.com"));
+    assert!(stripped.contains_key("m.space.parent", "!space:example.com"));
+
+    let mut events = state_map.stripped_events(&StrippedStateConfig::default(), "@bob:example.com");
+    events.sort();
+    assert_eq!(
+        events,
+        vec!["stripped bob", "stripped create", "stripped name"]
+    );
+}
@@ -0,0 +1,199 @@
+// Copyright 2018 New Vector Ltd
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not
+// use this file except in compliance with the License. You may obtain a copy of
+// the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS, WITHOUT
+// WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied. See the
+// License for the specific language governing permissions and limitations under
+// the License.
+
+//! Computation of the room summary returned to clients in `/sync`.
+
+use std::fmt::Debug;
+use std::hash::BuildHasher;
+
+use StateMap;
+
+/// The maximum number of heroes included in a [`RoomSummary`].
+pub const MAX_HEROES: usize = 5;
+
+/// The `membership` of an `m.room.member` event.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Membership {
+    Join,
+    Invite,
+    Knock,
+    Leave,
+    Ban,
+}
+
+/// Gives access to the membership of the values stored in the membership
+/// bucket of a `StateMap`.
+pub trait MembershipEvent {
+    fn membership(&self) -> Membership;
+
+    /// The position of the event in the room's stream, used to order heroes.
+    /// Members with the same stream ordering are ordered by user ID.
+    fn stream_ordering(&self) -> i64;
+}
+
+/// The summary of a room's membership, i.e. `m.heroes`,
+/// `m.joined_member_count` and `m.invited_member_count`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RoomSummary {
+    /// The user IDs of the members that can be used to name the room.
+    pub heroes: Vec<String>,
+    pub joined_member_count: usize,
+    pub invited_member_count: usize,
+}
+
+/// The first `MAX_HEROES` candidates seen, ordered by stream ordering and
+/// then user ID.
+struct Candidates<'a> {
+    members: Vec<(i64, &'a str)>,
+}
+
+impl<'a> Candidates<'a> {
+    fn new() -> Candidates<'a> {
+        Candidates {
+            members: Vec::with_capacity(MAX_HEROES),
+        }
+    }
+
+    fn offer(&mut self, ordering: i64, user: &'a str) {
+        let idx = match self.members.binary_search(&(ordering, user)) {
+            Ok(_) => return,
+            Err(idx) => idx,
+        };
+
+        if idx < MAX_HEROES {
+            if self.members.len() == MAX_HEROES {
+                self.members.pop();
+            }
+            self.members.insert(idx, (ordering, user));
+        }
+    }
+
+    fn into_user_ids(self) -> Vec<String> {
+        self.members
+            .into_iter()
+            .map(|(_, user)| user.to_string())
+            .collect()
+    }
+}
+
+/// Computes the summary of the room with the given state, as seen by `me`.
+///
+/// As per the spec, the heroes are the first [`MAX_HEROES`] joined or invited
+/// members by stream ordering, excluding `me`. If there are no such members,
+/// the heroes are instead picked from the members that have left or been
+/// banned.
+pub fn room_summary<E, S>(state_map: &StateMap<E, S>, me: &str) -> RoomSummary
+where
+    E: Debug + Clone + MembershipEvent,
+    S: BuildHasher + Clone,
+{
+    let mut summary = RoomSummary::default();
+    let mut current = Candidates::new();
+    let mut former = Candidates::new();
+
+    for (user, e) in state_map.iter_members() {
+        let candidates = match e.membership() {
+            Membership::Join => {
+                summary.joined_member_count += 1;
+                &mut current
+            }
+            Membership::Invite => {
+                summary.invited_member_count += 1;
+                &mut current
+            }
+            Membership::Leave | Membership::Ban => &mut former,
+            Membership::Knock => continue,
+        };
+
+        if user != me {
+            candidates.offer(e.stream_ordering(), user);
+        }
+    }
+
+    summary.heroes = if current.members.is_empty() {
+        former.into_user_ids()
+    } else {
+        current.into_user_ids()
+    };
+
+    summary
+}
+
+#[test]
+fn room_summary_test() {
+    use TYPE_MEMBERSHIP;
+
+    #[derive(Debug, Clone)]
+    struct Member(Membership, i64);
+
+    impl MembershipEvent for Member {
+        fn membership(&self) -> Membership {
+            self.0
+        }
+
+        fn stream_ordering(&self) -> i64 {
+            self.1
+        }
+    }
+
+    let mut state_map = StateMap::new();
+    state_map.insert(
+        TYPE_MEMBERSHIP,
+        "@me:example.com",
+        Member(Membership::Join, 1),
+    );
+    state_map.insert(
+        TYPE_MEMBERSHIP,
+        "@left:example.com",
+        Member(Membership::Leave, 2),
+    );
+
+    let summary = room_summary(&state_map, "@me:example.com");
+    assert_eq!(summary.heroes, vec!["@left:example.com"]);
+    assert_eq!(summary.joined_member_count, 1);
+
+    for i in 0..10 {
+        let membership = if i % 2 == 0 {
+            Membership::Join
+        } else {
+            Membership::Invite
+        };
+        state_map.insert(
+            TYPE_MEMBERSHIP,
+            &format!("@user{}:example.com", i),
+            Member(membership, 20 - i),
+        );
+    }
+    state_map.insert(
+        TYPE_MEMBERSHIP,
+        "@knock:example.com",
+        Member(Membership::Knock, 0),
+    );
+
+    let summary = room_summary(&state_map, "@me:example.com");
+    assert_eq!(
+        summary,
+        RoomSummary {
+            heroes: vec![
+                "@user9:example.com".to_string(),
+                "@user8:example.com".to_string(),
+                "@user7:example.com".to_string(),
+                "@user6:example.com".to_string(),
+                "@user5:example.com".to_string(),
+            ],
+            joined_member_count: 6,
+            invited_member_count: 5,
+        }
+    );
+}
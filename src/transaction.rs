@@ -0,0 +1,182 @@
+// Copyright 2018 New Vector Ltd
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not
+// use this file except in compliance with the License. You may obtain a copy of
+// the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS, WITHOUT
+// WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied. See the
+// License for the specific language governing permissions and limitations under
+// the License.
+
+//! Transactional edits of a `StateMap` that can be rolled back.
+
+use std::borrow::Borrow;
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::hash::BuildHasher;
+use std::ops::Deref;
+
+use StateMap;
+
+/// A guard over a `StateMap` which records the original value of every key
+/// modified through it, created by [`StateMap::transaction`].
+///
+/// Unless [`Transaction::commit`] is called, all the changes are undone when
+/// the guard is dropped. Reads can go through the guard, as it derefs to the
+/// underlying state map.
+pub struct Transaction<'a, E: Debug + Clone + 'a, S: BuildHasher + Clone + 'a> {
+    state_map: &'a mut StateMap<E, S>,
+    /// The value each touched key had before the transaction started, keyed
+    /// by type and then state key.
+    undo: HashMap<String, HashMap<String, Option<E>>>,
+}
+
+impl<E, S> StateMap<E, S>
+where
+    E: Debug + Clone,
+    S: BuildHasher + Clone,
+{
+    /// Starts a transaction on the map. Changes made through the returned
+    /// guard are rolled back when it is dropped, unless it is committed.
+    pub fn transaction(&mut self) -> Transaction<'_, E, S> {
+        Transaction {
+            state_map: self,
+            undo: HashMap::new(),
+        }
+    }
+}
+
+impl<'a, E, S> Transaction<'a, E, S>
+where
+    E: Debug + Clone,
+    S: BuildHasher + Clone,
+{
+    /// Records the current value of the key, if it hasn't already been
+    /// recorded in this transaction.
+    fn record(&mut self, t: &str, s: &str) {
+        if let Some(h) = self.undo.get(t) {
+            if h.contains_key(s) {
+                return;
+            }
+        }
+
+        let previous = self.state_map.get(t, s).cloned();
+        self.undo
+            .entry(t.to_string())
+            .or_default()
+            .insert(s.to_string(), previous);
+    }
+
+    /// Inserts the entry into the map, as [`StateMap::insert`].
+    pub fn insert(&mut self, t: &str, s: &str, value: E) -> Option<E> {
+        self.record(t, s);
+        self.state_map.insert(t, s, value)
+    }
+
+    /// Removes the entry from the map, as [`StateMap::remove`].
+    pub fn remove(&mut self, t: &str, s: &str) -> Option<E> {
+        self.record(t, s);
+        self.state_map.remove(t, s)
+    }
+
+    /// Gets a mutable reference to a value in the map, as
+    /// [`StateMap::get_mut`].
+    pub fn get_mut(&mut self, t: &str, s: &str) -> Option<&mut E> {
+        if self.state_map.contains_key(t, s) {
+            self.record(t, s);
+        }
+        self.state_map.get_mut(t, s)
+    }
+
+    /// Commits the changes, so that they are kept when the guard is dropped.
+    pub fn commit(mut self) {
+        self.undo.clear();
+    }
+
+    /// Undoes all changes made through the guard. This is the same as
+    /// dropping it.
+    pub fn rollback(self) {}
+}
+
+impl<'a, E, S> Transaction<'a, E, S>
+where
+    E: Debug + Clone + PartialEq,
+    S: BuildHasher + Clone,
+{
+    /// Inserts or removes the entry, as [`StateMap::add_or_remove`].
+    pub fn add_or_remove<F>(&mut self, t: &str, s: &str, v: F) -> Option<E>
+    where
+        F: Borrow<E>,
+    {
+        self.record(t, s);
+        self.state_map.add_or_remove(t, s, v)
+    }
+}
+
+impl<'a, E, S> Deref for Transaction<'a, E, S>
+where
+    E: Debug + Clone,
+    S: BuildHasher + Clone,
+{
+    type Target = StateMap<E, S>;
+
+    fn deref(&self) -> &StateMap<E, S> {
+        self.state_map
+    }
+}
+
+impl<'a, E, S> Drop for Transaction<'a, E, S>
+where
+    E: Debug + Clone,
+    S: BuildHasher + Clone,
+{
+    fn drop(&mut self) {
+        for (t, h) in self.undo.drain() {
+            for (s, previous) in h {
+                match previous {
+                    Some(e) => self.state_map.insert(&t, &s, e),
+                    None => self.state_map.remove(&t, &s),
+                };
+            }
+        }
+    }
+}
+
+#[test]
+fn transaction_test() {
+    use {TYPE_MEMBERSHIP, TYPE_POWER_LEVELS};
+
+    let mut state_map = StateMap::new();
+    state_map.insert(TYPE_POWER_LEVELS, "", 1);
+    state_map.insert(TYPE_MEMBERSHIP, "@alice:example.com", 2);
+
+    let original = state_map.clone();
+
+    {
+        let mut txn = state_map.transaction();
+        txn.insert(TYPE_POWER_LEVELS, "", 10);
+        txn.insert(TYPE_POWER_LEVELS, "", 11);
+        txn.remove(TYPE_MEMBERSHIP, "@alice:example.com");
+        txn.insert("test", "a", 3);
+        *txn.get_mut("test", "a").unwrap() = 4;
+        txn.add_or_remove(TYPE_MEMBERSHIP, "@bob:example.com", 5);
+
+        assert_eq!(txn.get(TYPE_POWER_LEVELS, ""), Some(&11));
+        assert_eq!(txn.len(), 3);
+    }
+
+    assert_eq!(state_map, original);
+
+    {
+        let mut txn = state_map.transaction();
+        txn.insert("test", "a", 3);
+        txn.commit();
+    }
+
+    assert_eq!(state_map.get("test", "a"), Some(&3));
+    assert_eq!(state_map.len(), 3);
+}
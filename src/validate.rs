@@ -0,0 +1,264 @@
+// Copyright 2018 New Vector Ltd
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not
+// use this file except in compliance with the License. You may obtain a copy of
+// the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS, WITHOUT
+// WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied. See the
+// License for the specific language governing permissions and limitations under
+// the License.
+
+//! Validation of the keys inserted into a `StateMap`.
+
+use std::error::Error;
+use std::fmt::{self, Debug, Display};
+use std::hash::BuildHasher;
+
+use {StateMap, TYPE_ALIASES, TYPE_MEMBERSHIP};
+
+/// The maximum length in bytes of an event type or state key.
+pub const MAX_KEY_LENGTH: usize = 255;
+
+/// The reason a key was rejected by [`StateMap::try_insert`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StateMapError {
+    /// The event type was empty.
+    EmptyType,
+    /// The event type was longer than [`MAX_KEY_LENGTH`] bytes.
+    TypeTooLong(usize),
+    /// The state key was longer than [`MAX_KEY_LENGTH`] bytes.
+    StateKeyTooLong(usize),
+    /// The state key of an `m.room.member` entry wasn't a valid user ID.
+    InvalidUserId(String),
+    /// The state key of an `m.room.aliases` entry wasn't a valid server name.
+    InvalidServerName(String),
+}
+
+impl Display for StateMapError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            StateMapError::EmptyType => write!(f, "event type is empty"),
+            StateMapError::TypeTooLong(len) => write!(
+                f,
+                "event type is {} bytes long, the maximum is {}",
+                len, MAX_KEY_LENGTH
+            ),
+            StateMapError::StateKeyTooLong(len) => write!(
+                f,
+                "state key is {} bytes long, the maximum is {}",
+                len, MAX_KEY_LENGTH
+            ),
+            StateMapError::InvalidUserId(ref s) => write!(f, "invalid user ID: {:?}", s),
+            StateMapError::InvalidServerName(ref s) => write!(f, "invalid server name: {:?}", s),
+        }
+    }
+}
+
+impl Error for StateMapError {}
+
+/// Checks that the key is valid for the bucket it would be stored in.
+fn validate_key(t: &str, s: &str) -> Result<(), StateMapError> {
+    if t.is_empty() {
+        return Err(StateMapError::EmptyType);
+    }
+    if t.len() > MAX_KEY_LENGTH {
+        return Err(StateMapError::TypeTooLong(t.len()));
+    }
+    if s.len() > MAX_KEY_LENGTH {
+        return Err(StateMapError::StateKeyTooLong(s.len()));
+    }
+
+    match t {
+        TYPE_MEMBERSHIP if !is_valid_user_id(s) => Err(StateMapError::InvalidUserId(s.to_string())),
+        TYPE_ALIASES if !is_valid_server_name(s) => {
+            Err(StateMapError::InvalidServerName(s.to_string()))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Whether the string is a user ID, i.e. `@localpart:server_name`.
+///
+/// The localpart is allowed to use the historical grammar, i.e. any printable
+/// ASCII other than `:`, as rooms may contain such users.
+fn is_valid_user_id(s: &str) -> bool {
+    let rest = match s.strip_prefix('@') {
+        Some(rest) => rest,
+        None => return false,
+    };
+
+    let (localpart, server_name) = match rest.find(':') {
+        Some(idx) => (&rest[..idx], &rest[idx + 1..]),
+        None => return false,
+    };
+
+    !localpart.is_empty()
+        && localpart.bytes().all(|b| (0x21..=0x7E).contains(&b))
+        && is_valid_server_name(server_name)
+}
+
+/// Whether the string is a server name, i.e. a hostname or IP literal and an
+/// optional port.
+fn is_valid_server_name(s: &str) -> bool {
+    let (host, port) = if s.starts_with('[') {
+        let end = match s.find(']') {
+            Some(end) => end,
+            None => return false,
+        };
+        let ipv6 = &s[1..end];
+        if ipv6.is_empty()
+            || !ipv6
+                .bytes()
+                .all(|b| b.is_ascii_hexdigit() || b == b':' || b == b'.')
+        {
+            return false;
+        }
+        (&s[..=end], &s[end + 1..])
+    } else {
+        match s.find(':') {
+            Some(idx) => (&s[..idx], &s[idx..]),
+            None => (s, ""),
+        }
+    };
+
+    let host_valid = host.starts_with('[')
+        || (!host.is_empty()
+            && host
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'.'));
+
+    let port_valid = port.is_empty()
+        || match port.strip_prefix(':') {
+            // `parse` alone would also accept a leading `+`.
+            Some(digits) => {
+                digits.len() <= 5
+                    && digits.bytes().all(|b| b.is_ascii_digit())
+                    && digits.parse::<u16>().is_ok()
+            }
+            None => false,
+        };
+
+    host_valid && port_valid
+}
+
+impl<E, S> StateMap<E, S>
+where
+    E: Debug + Clone,
+    S: BuildHasher + Clone,
+{
+    /// Inserts the entry into the map as [`StateMap::insert`], unless the key
+    /// is invalid.
+    ///
+    /// Both the type and state key must be at most [`MAX_KEY_LENGTH`] bytes
+    /// long, and the type must not be empty. The state keys of
+    /// `m.room.member` entries must be user IDs, and those of
+    /// `m.room.aliases` entries server names.
+    pub fn try_insert(&mut self, t: &str, s: &str, value: E) -> Result<Option<E>, StateMapError> {
+        validate_key(t, s)?;
+        Ok(self.insert(t, s, value))
+    }
+
+    /// Inserts all the entries into the map, stopping at the first invalid
+    /// key. See [`StateMap::try_insert`].
+    pub fn try_extend<I, T>(&mut self, iter: I) -> Result<(), StateMapError>
+    where
+        I: IntoIterator<Item = ((T, T), E)>,
+        T: AsRef<str>,
+    {
+        for ((t, s), e) in iter {
+            self.try_insert(t.as_ref(), s.as_ref(), e)?;
+        }
+        Ok(())
+    }
+}
+
+impl<E, S> StateMap<E, S>
+where
+    E: Debug + Clone,
+    S: BuildHasher + Clone + Default,
+{
+    /// Builds a state map from the entries, failing on the first invalid key.
+    /// See [`StateMap::try_insert`].
+    ///
+    /// Unlike this, collecting into a `StateMap` doesn't validate keys.
+    pub fn try_from_iter<I, T>(iter: I) -> Result<StateMap<E, S>, StateMapError>
+    where
+        I: IntoIterator<Item = ((T, T), E)>,
+        T: AsRef<str>,
+    {
+        let mut state_map = StateMap::with_hasher(S::default());
+        state_map.try_extend(iter)?;
+        Ok(state_map)
+    }
+}
+
+#[test]
+fn try_insert_test() {
+    use TYPE_THIRD_PARTY_INVITE;
+
+    let mut state_map = StateMap::new();
+
+    for &user in &[
+        "@alice:example.com",
+        "@alice:example.com:8448",
+        "@Alice=Old/Style+1:127.0.0.1",
+        "@bob:[::1]:8448",
+        "@carol:1.2.3.4:65535",
+    ] {
+        assert_eq!(state_map.try_insert(TYPE_MEMBERSHIP, user, 1), Ok(None));
+    }
+
+    for &user in &[
+        "alice:example.com",
+        "@alice",
+        "@:example.com",
+        "@alice:",
+        "@al ice:example.com",
+        "@alice:exa_mple.com",
+        "@alice:example.com:port",
+        "@alice:example.com:+80",
+        "@a:1.2.3.4:99999",
+        "@alice:[::1",
+    ] {
+        assert_eq!(
+            state_map.try_insert(TYPE_MEMBERSHIP, user, 1),
+            Err(StateMapError::InvalidUserId(user.to_string()))
+        );
+    }
+
+    assert_eq!(
+        state_map.try_insert(TYPE_ALIASES, "example.com", 1),
+        Ok(None)
+    );
+    assert_eq!(
+        state_map.try_insert(TYPE_ALIASES, "", 1),
+        Err(StateMapError::InvalidServerName(String::new()))
+    );
+    assert_eq!(
+        state_map.try_insert(TYPE_THIRD_PARTY_INVITE, "", 1),
+        Ok(None)
+    );
+    assert_eq!(
+        state_map.try_insert("", "", 1),
+        Err(StateMapError::EmptyType)
+    );
+
+    let long = "a".repeat(MAX_KEY_LENGTH + 1);
+    assert_eq!(
+        state_map.try_insert(&long, "", 1),
+        Err(StateMapError::TypeTooLong(MAX_KEY_LENGTH + 1))
+    );
+    assert_eq!(
+        state_map.try_insert("test", &long, 1),
+        Err(StateMapError::StateKeyTooLong(MAX_KEY_LENGTH + 1))
+    );
+    assert_eq!(state_map.len(), 7);
+
+    let result: Result<StateMap<i32>, _> =
+        StateMap::try_from_iter(vec![(("test", "a"), 1), ((TYPE_MEMBERSHIP, "bad"), 2)]);
+    assert_eq!(result, Err(StateMapError::InvalidUserId("bad".to_string())));
+}
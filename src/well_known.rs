@@ -0,0 +1,322 @@
+// Copyright 2018 New Vector Ltd
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not
+// use this file except in compliance with the License. You may obtain a copy of
+// the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS, WITHOUT
+// WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied. See the
+// License for the specific language governing permissions and limitations under
+// the License.
+
+//! Fixed size storage for the entries with well known types and empty state
+//! keys, indexed by [`WellKnownEmptyKeys::index`].
+
+use std::array;
+use std::fmt::{self, Debug};
+use std::iter::Enumerate;
+use std::mem;
+use std::slice;
+
+use WellKnownEmptyKeys;
+
+const COUNT: usize = WellKnownEmptyKeys::ALL.len();
+
+/// A map from `WellKnownEmptyKeys` to values, stored inline as an array so
+/// that lookups don't need to hash the key.
+///
+/// Which slots are filled is also tracked in a bitmask, so that e.g. the
+/// length doesn't need to scan the array.
+#[derive(Clone, PartialEq, Eq)]
+pub(crate) struct WellKnownMap<E> {
+    entries: [Option<E>; COUNT],
+    present: u16,
+}
+
+impl<E> WellKnownMap<E> {
+    pub fn new() -> WellKnownMap<E> {
+        WellKnownMap {
+            entries: Default::default(),
+            present: 0,
+        }
+    }
+
+    fn bit(key: WellKnownEmptyKeys) -> u16 {
+        1 << key.index()
+    }
+
+    pub fn get(&self, key: WellKnownEmptyKeys) -> Option<&E> {
+        self.entries[key.index()].as_ref()
+    }
+
+    pub fn get_mut(&mut self, key: WellKnownEmptyKeys) -> Option<&mut E> {
+        self.entries[key.index()].as_mut()
+    }
+
+    pub fn contains_key(&self, key: WellKnownEmptyKeys) -> bool {
+        self.present & WellKnownMap::<E>::bit(key) != 0
+    }
+
+    pub fn insert(&mut self, key: WellKnownEmptyKeys, value: E) -> Option<E> {
+        self.present |= WellKnownMap::<E>::bit(key);
+        self.entries[key.index()].replace(value)
+    }
+
+    pub fn remove(&mut self, key: WellKnownEmptyKeys) -> Option<E> {
+        self.present &= !WellKnownMap::<E>::bit(key);
+        self.entries[key.index()].take()
+    }
+
+    /// Gets the value for the key, inserting the result of `f` if there isn't
+    /// one.
+    pub fn get_or_insert_with<F>(&mut self, key: WellKnownEmptyKeys, f: F) -> &mut E
+    where
+        F: FnOnce() -> E,
+    {
+        self.present |= WellKnownMap::<E>::bit(key);
+        self.entries[key.index()].get_or_insert_with(f)
+    }
+
+    pub fn len(&self) -> usize {
+        self.present.count_ones() as usize
+    }
+
+    pub fn retain<F>(&mut self, mut f: F)
+    where
+        F: FnMut(WellKnownEmptyKeys, &mut E) -> bool,
+    {
+        for (i, slot) in self.entries.iter_mut().enumerate() {
+            let keep = match *slot {
+                Some(ref mut e) => f(WellKnownEmptyKeys::ALL[i], e),
+                None => continue,
+            };
+            if !keep {
+                *slot = None;
+                self.present &= !(1 << i);
+            }
+        }
+    }
+
+    pub fn iter(&self) -> Iter<'_, E> {
+        Iter {
+            entries: self.entries.iter().enumerate(),
+            remaining: self.len(),
+        }
+    }
+
+    pub fn iter_mut(&mut self) -> IterMut<'_, E> {
+        IterMut {
+            remaining: self.len(),
+            entries: self.entries.iter_mut().enumerate(),
+        }
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = WellKnownEmptyKeys> + '_ {
+        self.iter().map(|(k, _)| k)
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &E> {
+        self.iter().map(|(_, e)| e)
+    }
+
+    pub fn values_mut(&mut self) -> impl Iterator<Item = &mut E> {
+        self.iter_mut().map(|(_, e)| e)
+    }
+
+    /// Removes all entries from the map, returning them as an iterator. The
+    /// map is empty once the iterator is dropped, even if it was not fully
+    /// consumed.
+    pub fn drain(&mut self) -> Drain<'_, E> {
+        let remaining = self.len();
+        self.present = 0;
+
+        Drain {
+            entries: self.entries.iter_mut().enumerate(),
+            remaining,
+        }
+    }
+
+    /// Converts the map into one with a different value type by applying `f`
+    /// to each entry, stopping at the first error.
+    pub fn try_map<F, Err, M>(self, mut f: M) -> Result<WellKnownMap<F>, Err>
+    where
+        M: FnMut(WellKnownEmptyKeys, E) -> Result<F, Err>,
+    {
+        let mut mapped = WellKnownMap::new();
+        for (k, e) in self {
+            mapped.insert(k, f(k, e)?);
+        }
+        Ok(mapped)
+    }
+}
+
+impl<E> Default for WellKnownMap<E> {
+    fn default() -> WellKnownMap<E> {
+        WellKnownMap::new()
+    }
+}
+
+impl<E: Debug> Debug for WellKnownMap<E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
+
+impl<E> IntoIterator for WellKnownMap<E> {
+    type Item = (WellKnownEmptyKeys, E);
+    type IntoIter = IntoIter<E>;
+
+    fn into_iter(self) -> IntoIter<E> {
+        IntoIter {
+            remaining: self.len(),
+            entries: IntoIterator::into_iter(self.entries).enumerate(),
+        }
+    }
+}
+
+/// Returns the next filled slot from an iterator over the slots.
+fn next_filled<T, I>(entries: &mut I, remaining: &mut usize) -> Option<(WellKnownEmptyKeys, T)>
+where
+    I: Iterator<Item = (usize, Option<T>)>,
+{
+    for (i, slot) in entries {
+        if let Some(e) = slot {
+            *remaining -= 1;
+            return Some((WellKnownEmptyKeys::ALL[i], e));
+        }
+    }
+    None
+}
+
+pub(crate) struct Iter<'a, E: 'a> {
+    entries: Enumerate<slice::Iter<'a, Option<E>>>,
+    remaining: usize,
+}
+
+impl<'a, E> Iterator for Iter<'a, E> {
+    type Item = (WellKnownEmptyKeys, &'a E);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        next_filled(
+            &mut (&mut self.entries).map(|(i, e)| (i, e.as_ref())),
+            &mut self.remaining,
+        )
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<'a, E> ExactSizeIterator for Iter<'a, E> {}
+
+pub(crate) struct IterMut<'a, E: 'a> {
+    entries: Enumerate<slice::IterMut<'a, Option<E>>>,
+    remaining: usize,
+}
+
+impl<'a, E> Iterator for IterMut<'a, E> {
+    type Item = (WellKnownEmptyKeys, &'a mut E);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        next_filled(
+            &mut (&mut self.entries).map(|(i, e)| (i, e.as_mut())),
+            &mut self.remaining,
+        )
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<'a, E> ExactSizeIterator for IterMut<'a, E> {}
+
+pub(crate) struct IntoIter<E> {
+    entries: Enumerate<array::IntoIter<Option<E>, COUNT>>,
+    remaining: usize,
+}
+
+impl<E> Iterator for IntoIter<E> {
+    type Item = (WellKnownEmptyKeys, E);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        next_filled(&mut self.entries, &mut self.remaining)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<E> ExactSizeIterator for IntoIter<E> {}
+
+pub(crate) struct Drain<'a, E: 'a> {
+    entries: Enumerate<slice::IterMut<'a, Option<E>>>,
+    remaining: usize,
+}
+
+impl<'a, E> Iterator for Drain<'a, E> {
+    type Item = (WellKnownEmptyKeys, E);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        next_filled(
+            &mut (&mut self.entries).map(|(i, e)| (i, e.take())),
+            &mut self.remaining,
+        )
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<'a, E> ExactSizeIterator for Drain<'a, E> {}
+
+impl<'a, E> Drop for Drain<'a, E> {
+    fn drop(&mut self) {
+        for (_, slot) in &mut self.entries {
+            mem::drop(slot.take());
+        }
+    }
+}
+
+#[test]
+fn well_known_map_test() {
+    let mut map = WellKnownMap::new();
+    assert_eq!(map.len(), 0);
+
+    assert_eq!(map.insert(WellKnownEmptyKeys::Topic, 1), None);
+    assert_eq!(map.insert(WellKnownEmptyKeys::Create, 2), None);
+    assert_eq!(map.insert(WellKnownEmptyKeys::Topic, 3), Some(1));
+    *map.get_or_insert_with(WellKnownEmptyKeys::Encryption, || 4) += 1;
+    assert_eq!(map.len(), 3);
+    assert!(map.contains_key(WellKnownEmptyKeys::Create));
+    assert!(!map.contains_key(WellKnownEmptyKeys::Name));
+
+    // Entries are returned in key order.
+    let entries: Vec<_> = map.iter().map(|(k, e)| (k, *e)).collect();
+    assert_eq!(
+        entries,
+        vec![
+            (WellKnownEmptyKeys::Create, 2),
+            (WellKnownEmptyKeys::Topic, 3),
+            (WellKnownEmptyKeys::Encryption, 5),
+        ]
+    );
+
+    map.retain(|k, _| k != WellKnownEmptyKeys::Create);
+    assert_eq!(map.len(), 2);
+    assert_eq!(map.get(WellKnownEmptyKeys::Create), None);
+
+    {
+        let mut drain = map.drain();
+        assert_eq!(drain.len(), 2);
+        assert_eq!(drain.next(), Some((WellKnownEmptyKeys::Topic, 3)));
+    }
+    assert_eq!(map.len(), 0);
+    assert_eq!(map, WellKnownMap::new());
+}
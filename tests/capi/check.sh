@@ -0,0 +1,37 @@
+#!/bin/sh
+# Copyright 2018 New Vector Ltd
+#
+# Licensed under the Apache License, Version 2.0 (the "License"); you may not
+# use this file except in compliance with the License. You may obtain a copy of
+# the License at
+#
+# http://www.apache.org/licenses/LICENSE-2.0
+#
+# Unless required by applicable law or agreed to in writing, software
+# distributed under the License is distributed on an "AS IS" BASIS, WITHOUT
+# WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied. See the
+# License for the specific language governing permissions and limitations under
+# the License.
+
+# Checks that include/state_map.h matches what cbindgen generates from
+# src/capi.rs, then builds and runs tests/capi/test_state_map.c against the
+# library. Run from the repository root, with cbindgen and a C compiler
+# installed.
+
+set -eu
+
+header=$(mktemp)
+trap 'rm -f "$header"' EXIT
+
+cbindgen --quiet --config cbindgen.toml --output "$header"
+if ! diff -u include/state_map.h "$header"; then
+    echo "include/state_map.h is out of date, regenerate it with:" >&2
+    echo "    cbindgen --config cbindgen.toml --output include/state_map.h" >&2
+    exit 1
+fi
+
+cargo rustc --lib --features capi --crate-type cdylib
+# Built with NDEBUG to make sure no check relies on `assert`.
+${CC:-cc} -Wall -Werror -DNDEBUG -Iinclude tests/capi/test_state_map.c \
+    -Ltarget/debug -lstate_map -o target/test_state_map
+LD_LIBRARY_PATH=target/debug target/test_state_map
@@ -0,0 +1,82 @@
+/*
+ * Copyright 2018 New Vector Ltd
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License"); you may not
+ * use this file except in compliance with the License. You may obtain a copy of
+ * the License at
+ *
+ * http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS, WITHOUT
+ * WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied. See the
+ * License for the specific language governing permissions and limitations under
+ * the License.
+ */
+
+/*
+ * Exercises the C API from C. Check the header, then build and run this with:
+ *
+ *     tests/capi/check.sh
+ */
+
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+#include "state_map.h"
+
+/* Unlike `assert` this is kept with NDEBUG, as most checks have side effects */
+#define CHECK(cond)                                                           \
+    do {                                                                      \
+        if (!(cond)) {                                                        \
+            fprintf(stderr, "%s:%d: check failed: %s\n", __FILE__, __LINE__, \
+                    #cond);                                                   \
+            exit(1);                                                          \
+        }                                                                     \
+    } while (0)
+
+static void count_members(void *ctx, const char *event_type, size_t event_type_len,
+                          const char *state_key, size_t state_key_len,
+                          const char *event_id) {
+    (void)state_key;
+    (void)state_key_len;
+    (void)event_id;
+
+    if (event_type_len == strlen("m.room.member") &&
+        memcmp(event_type, "m.room.member", event_type_len) == 0) {
+        *(size_t *)ctx += 1;
+    }
+}
+
+int main(void) {
+    CStateMap *map = state_map_new();
+    CHECK(map != NULL);
+    CHECK(state_map_len(map) == 0);
+
+    CHECK(state_map_insert(map, "m.room.create", "", "$create"));
+    CHECK(state_map_insert(map, "m.room.member", "@alice:example.com", "$alice"));
+    CHECK(state_map_insert(map, "m.room.member", "@bob:example.com", "$bob"));
+    CHECK(state_map_insert(map, "org.example.custom", "", "$custom"));
+    CHECK(!state_map_insert(map, "m.room.member", NULL, "$nobody"));
+    CHECK(state_map_len(map) == 4);
+
+    /* Replacing an entry doesn't change the length */
+    CHECK(state_map_insert(map, "m.room.member", "@bob:example.com", "$bob2"));
+    CHECK(state_map_len(map) == 4);
+
+    CHECK(strcmp(state_map_get(map, "m.room.create", ""), "$create") == 0);
+    CHECK(strcmp(state_map_get(map, "m.room.member", "@bob:example.com"), "$bob2") == 0);
+    CHECK(strcmp(state_map_get(map, "org.example.custom", ""), "$custom") == 0);
+    CHECK(state_map_get(map, "m.room.member", "@carol:example.com") == NULL);
+
+    size_t members = 0;
+    state_map_foreach(map, count_members, &members);
+    CHECK(members == 2);
+
+    state_map_free(map);
+    state_map_free(NULL);
+
+    printf("ok\n");
+    return 0;
+}